@@ -0,0 +1,82 @@
+use std::sync::Mutex;
+
+use crate::Capacitor;
+use crate::configs::{ AccountsCommand, Config };
+use crate::contracts::{ self, ContractUpdate, WatchedContract };
+use crate::database;
+use crate::migrations;
+
+/// Recorded as `added_by` for contracts added from the command line.
+const ADDED_BY: &str = "cli";
+
+fn not_watched(config: &Config, account_id: &str) -> String {
+    match config.contracts.iter().any(|contract| contract == account_id) {
+        true => format!("Account '{}' comes from the config file's contracts, change it there", account_id),
+        false => format!("Account '{}' is not watched", account_id),
+    }
+}
+
+async fn set_enabled(capacitor_ins: &Mutex<Capacitor>, config: &Config, account_id: &str, enabled: bool) -> Result<WatchedContract, String> {
+    let update = ContractUpdate { enabled: Some(enabled), ..ContractUpdate::default() };
+
+    Capacitor::update_contract(capacitor_ins, account_id, &update).await?
+        .ok_or_else(|| not_watched(config, account_id))
+}
+
+/// `accounts`: changes the contracts stored in `allowed_account_ids` the way the admin API
+/// does, without starting the node. A running capacitor picks the changes up on its next
+/// config reload.
+pub(crate) async fn run(command: AccountsCommand, config: &Config) -> Result<(), String> {
+    if let Some(account_id) = command.account_id() {
+        contracts::validate_account_id(account_id)?;
+    }
+
+    // Only the stored contracts, the config file's ones cannot be changed from here
+    let client = database::try_connect(&config.mongodb).await?;
+    let mut stored = Capacitor::new(client, &config.mongodb.database, vec![]);
+    migrations::run(&stored.database()).await?;
+    stored.load().await;
+    let capacitor_ins = Mutex::new(stored);
+
+    match command {
+        AccountsCommand::List => {
+            let capacitor_unwrapped = capacitor_ins.lock().unwrap();
+            let mut contracts = capacitor_unwrapped.contracts();
+            for account_id in &config.contracts {
+                if capacitor_unwrapped.contract(account_id).is_none() {
+                    contracts.push(WatchedContract::new(account_id.clone(), "config"));
+                }
+            }
+            contracts.sort_by(|a, b| a.account_id.cmp(&b.account_id));
+
+            println!("📝 {} watched contracts:", contracts.len());
+            for contract in contracts {
+                let added_by = contract.added_by.as_deref().unwrap_or("unknown");
+                println!("  - {} ({}; added by {})", contract.account_id, contract.describe(), added_by);
+            }
+        }
+        AccountsCommand::Add { account_id, label, from_height } => {
+            let mut contract = WatchedContract::new(account_id.clone(), ADDED_BY);
+            contract.apply(&ContractUpdate { label, start_height: from_height, ..ContractUpdate::default() });
+
+            match Capacitor::add_account_id(&capacitor_ins, contract).await? {
+                true => println!("✅ Account '{}' was added to the database", account_id),
+                false => return Err(format!("Account '{}' is already watched", account_id)),
+            }
+        }
+        AccountsCommand::Remove { account_id } => match Capacitor::remove_contract(&capacitor_ins, &account_id).await? {
+            true => println!("✅ Account '{}' is no longer watched", account_id),
+            false => return Err(not_watched(config, &account_id)),
+        },
+        AccountsCommand::Pause { account_id } => {
+            set_enabled(&capacitor_ins, config, &account_id, false).await?;
+            println!("✅ Account '{}' is paused", account_id);
+        }
+        AccountsCommand::Resume { account_id } => {
+            set_enabled(&capacitor_ins, config, &account_id, true).await?;
+            println!("✅ Account '{}' is watched again", account_id);
+        }
+    }
+
+    Ok(())
+}
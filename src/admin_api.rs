@@ -0,0 +1,366 @@
+use actix_web::{ guard, web, HttpMessage, HttpRequest, HttpResponse, Route, dev::HttpServiceFactory, error::InternalError, http::{ Method, StatusCode } };
+use bson::Bson;
+use serde::Deserialize;
+use serde_json::json;
+use qstring::{ QString };
+
+use crate::Capacitor;
+use crate::auth::{ AuthenticatedToken, RequireScope, Scope };
+use crate::backfill::BackfillJob;
+use crate::contracts::{ self, ContractUpdate, NewContract };
+use crate::http_server::AppState;
+use crate::reload::ReloadError;
+use crate::webhooks::{ NewWebhook, WebhookUpdate };
+
+/// JSON error body shared by every admin endpoint: `{ "error": <code>, "message": <details> }`.
+pub fn api_error(status: StatusCode, error: &str, message: impl ToString) -> HttpResponse {
+    HttpResponse::build(status).json(json!({
+        "error": error,
+        "message": message.to_string(),
+    }))
+}
+
+/// Name of the token the request was authenticated with, recorded as `added_by`.
+pub(crate) fn token_name(req: &HttpRequest) -> String {
+    req.extensions().get::<AuthenticatedToken>().map(|token| token.name.clone()).unwrap_or("api".to_string())
+}
+
+/// Whether the request's token also carries `scope`, for routes whose options need more than the route's scope.
+pub(crate) fn has_scope(req: &HttpRequest, scope: Scope) -> bool {
+    req.extensions().get::<AuthenticatedToken>().map_or(false, |token| token.scopes.contains(&scope))
+}
+
+async fn list_contracts(data: web::Data<AppState>) -> HttpResponse {
+    let capacitor_ins = data.capacitor_ins.lock().unwrap();
+    HttpResponse::Ok().json(capacitor_ins.contracts())
+}
+
+async fn create_contract(data: web::Data<AppState>, req: HttpRequest, body: web::Json<NewContract>) -> HttpResponse {
+    if let Err(err) = contracts::validate_account_id(&body.account_id) {
+        return api_error(StatusCode::BAD_REQUEST, "invalid_account_id", err);
+    }
+
+    let contract = body.into_inner().into_contract(&token_name(&req));
+    let account_id = contract.account_id.clone();
+
+    match Capacitor::add_account_id(&data.capacitor_ins, contract).await {
+        Ok(true) => HttpResponse::Created().json(data.capacitor_ins.lock().unwrap().contract(&account_id)),
+        Ok(false) => api_error(StatusCode::CONFLICT, "already_watched", format!("Account '{}' is already watched", account_id)),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+/// Validates the `{account_id}` path segment.
+fn path_account_id(path: &web::Path<String>) -> Result<String, HttpResponse> {
+    let account_id = path.as_str();
+
+    contracts::validate_account_id(account_id)
+        .map(|_| account_id.to_string())
+        .map_err(|err| api_error(StatusCode::BAD_REQUEST, "invalid_account_id", err))
+}
+
+fn not_watched(account_id: &str) -> HttpResponse {
+    api_error(StatusCode::NOT_FOUND, "not_found", format!("Account '{}' is not watched", account_id))
+}
+
+async fn get_contract(data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    let account_id = match path_account_id(&path) {
+        Ok(account_id) => account_id,
+        Err(response) => return response,
+    };
+
+    let capacitor_ins = data.capacitor_ins.lock().unwrap();
+    match capacitor_ins.contract(&account_id) {
+        Some(contract) => HttpResponse::Ok().json(contract),
+        None => not_watched(&account_id),
+    }
+}
+
+async fn update_contract(data: web::Data<AppState>, path: web::Path<String>, body: web::Json<ContractUpdate>) -> HttpResponse {
+    let account_id = match path_account_id(&path) {
+        Ok(account_id) => account_id,
+        Err(response) => return response,
+    };
+
+    match Capacitor::update_contract(&data.capacitor_ins, &account_id, &body).await {
+        Ok(Some(contract)) => HttpResponse::Ok().json(contract),
+        Ok(None) => not_watched(&account_id),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+async fn delete_contract(data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    let account_id = match path_account_id(&path) {
+        Ok(account_id) => account_id,
+        Err(response) => return response,
+    };
+
+    match Capacitor::remove_contract(&data.capacitor_ins, &account_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => not_watched(&account_id),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+async fn list_discovered(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let query_string = QString::from(req.query_string());
+    match data.discovery.list(query_string.get("include_promoted") == Some("true")).await {
+        Ok(discovered) => HttpResponse::Ok().json(discovered),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+/// Moves a discovered contract onto the watch list. The optional body takes the
+/// same fields as `PATCH /contracts/{account_id}`.
+async fn promote_discovered(data: web::Data<AppState>, req: HttpRequest, path: web::Path<String>, body: Option<web::Json<ContractUpdate>>) -> HttpResponse {
+    let account_id = match path_account_id(&path) {
+        Ok(account_id) => account_id,
+        Err(response) => return response,
+    };
+
+    match data.discovery.get(&account_id).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return api_error(StatusCode::NOT_FOUND, "not_found", format!("Account '{}' was not discovered", account_id)),
+        Err(err) => return api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+
+    let new_contract = NewContract {
+        account_id: account_id.clone(),
+        fields: body.map(web::Json::into_inner).unwrap_or_default(),
+    };
+
+    if let Err(err) = Capacitor::add_account_id(&data.capacitor_ins, new_contract.into_contract(&token_name(&req))).await {
+        return api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err);
+    }
+    if let Err(err) = data.discovery.mark_promoted(&account_id).await {
+        return api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err);
+    }
+
+    HttpResponse::Ok().json(data.capacitor_ins.lock().unwrap().contract(&account_id))
+}
+
+#[derive(Deserialize)]
+struct ReplayRequest {
+    sink: Option<String>,
+    event_ids: Option<Vec<String>>,
+}
+
+/// Most recent dead letters, optionally of one `sink`, at most `limit` (default 100).
+async fn list_dead_letters(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let query_string = QString::from(req.query_string());
+    let limit = match query_string.get("limit").map(str::parse::<i64>) {
+        None => 100,
+        Some(Ok(limit)) if limit > 0 => limit,
+        Some(_) => return api_error(StatusCode::BAD_REQUEST, "invalid_limit", "`limit` must be a positive number"),
+    };
+
+    match data.sink_router.dead_letters(query_string.get("sink"), limit).await {
+        Ok(dead_letters) => HttpResponse::Ok().json(dead_letters.into_iter().map(|dead_letter| Bson::Document(dead_letter).into_relaxed_extjson()).collect::<Vec<_>>()),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+/// Queues dead letters on their sink again, all of them unless the body narrows
+/// them down by `sink` or `event_ids`.
+async fn replay_dead_letters(data: web::Data<AppState>, body: Option<web::Json<ReplayRequest>>) -> HttpResponse {
+    let request = body.map(web::Json::into_inner).unwrap_or(ReplayRequest { sink: None, event_ids: None });
+
+    match data.sink_router.replay_dead_letters(request.sink.as_deref(), request.event_ids.as_deref()).await {
+        Ok(replayed) => HttpResponse::Ok().json(json!({ "replayed": replayed })),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "replay_failed", err),
+    }
+}
+
+async fn list_webhooks(data: web::Data<AppState>) -> HttpResponse {
+    match data.webhooks.list().await {
+        Ok(subscriptions) => HttpResponse::Ok().json(subscriptions.iter().map(|subscription| subscription.view()).collect::<Vec<_>>()),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+async fn create_webhook(data: web::Data<AppState>, req: HttpRequest, body: web::Json<NewWebhook>) -> HttpResponse {
+    if let Err(err) = body.validate() {
+        return api_error(StatusCode::BAD_REQUEST, "invalid_webhook", err);
+    }
+
+    let subscription = body.into_inner().into_subscription(&token_name(&req));
+    if let Err(err) = data.webhooks.insert(&subscription).await {
+        return api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err);
+    }
+    subscription.route(&data.sink_router, data.webhooks.database());
+
+    HttpResponse::Created().json(subscription.view())
+}
+
+fn webhook_not_found(id: &str) -> HttpResponse {
+    api_error(StatusCode::NOT_FOUND, "not_found", format!("Webhook subscription '{}' does not exist", id))
+}
+
+async fn get_webhook(data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    match data.webhooks.get(&path).await {
+        Ok(Some(subscription)) => HttpResponse::Ok().json(subscription.view()),
+        Ok(None) => webhook_not_found(&path),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+/// Changes a subscription; `{ "enabled": false }` pauses it. Delivery picks up the change right away.
+async fn update_webhook(data: web::Data<AppState>, path: web::Path<String>, body: web::Json<WebhookUpdate>) -> HttpResponse {
+    if let Err(err) = body.validate() {
+        return api_error(StatusCode::BAD_REQUEST, "invalid_webhook", err);
+    }
+
+    let mut subscription = match data.webhooks.get(&path).await {
+        Ok(Some(subscription)) => subscription,
+        Ok(None) => return webhook_not_found(&path),
+        Err(err) => return api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    };
+    subscription.apply(&body);
+
+    if let Err(err) = data.webhooks.replace(&subscription).await {
+        return api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err);
+    }
+    subscription.route(&data.sink_router, data.webhooks.database());
+
+    HttpResponse::Ok().json(subscription.view())
+}
+
+async fn delete_webhook(data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    match data.webhooks.delete(&path).await {
+        Ok(true) => {
+            data.sink_router.remove_webhook(&path);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => webhook_not_found(&path),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+/// Most recent delivery attempts of a subscription, at most `limit` (default 100).
+async fn list_webhook_deliveries(data: web::Data<AppState>, req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    let limit = match QString::from(req.query_string()).get("limit").map(str::parse::<i64>) {
+        None => 100,
+        Some(Ok(limit)) if limit > 0 => limit,
+        Some(_) => return api_error(StatusCode::BAD_REQUEST, "invalid_limit", "`limit` must be a positive number"),
+    };
+
+    match data.webhooks.get(&path).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return webhook_not_found(&path),
+        Err(err) => return api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+
+    match data.webhooks.deliveries(&path, limit).await {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries.into_iter().map(|delivery| Bson::Document(delivery).into_relaxed_extjson()).collect::<Vec<_>>()),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+#[derive(Deserialize)]
+struct BackfillRequest {
+    account_id: String,
+    from_height: u64,
+    to_height: Option<u64>,
+}
+
+/// Starts backfilling a watched contract from `from_height` up to `to_height`, by default
+/// the last block processed live. Shared with `/config/add_account?backfill_from=`.
+pub(crate) async fn start_backfill(data: &AppState, req: &HttpRequest, account_id: &str, from_height: u64, to_height: Option<u64>) -> Result<BackfillJob, HttpResponse> {
+    if data.capacitor_ins.lock().unwrap().contract(account_id).is_none() {
+        return Err(not_watched(account_id));
+    }
+
+    let to_height = match to_height {
+        Some(to_height) => to_height,
+        None => data.backfill.default_to_height().await.map_err(|err| api_error(StatusCode::SERVICE_UNAVAILABLE, "node_unavailable", err))?,
+    };
+    if from_height > to_height {
+        return Err(api_error(StatusCode::BAD_REQUEST, "invalid_height", format!("`from_height` {} is past `to_height` {}", from_height, to_height)));
+    }
+
+    let job = BackfillJob::new(account_id.to_string(), from_height, to_height, &token_name(req));
+    data.backfill.start(job).await.map_err(|err| api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err))
+}
+
+async fn list_jobs(data: web::Data<AppState>) -> HttpResponse {
+    match data.backfill.list().await {
+        Ok(jobs) => HttpResponse::Ok().json(jobs.iter().map(BackfillJob::view).collect::<Vec<_>>()),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+async fn create_job(data: web::Data<AppState>, req: HttpRequest, body: web::Json<BackfillRequest>) -> HttpResponse {
+    match start_backfill(&data, &req, &body.account_id, body.from_height, body.to_height).await {
+        Ok(job) => HttpResponse::Created().json(job.view()),
+        Err(response) => response,
+    }
+}
+
+fn job_not_found(id: &str) -> HttpResponse {
+    api_error(StatusCode::NOT_FOUND, "not_found", format!("Job '{}' does not exist", id))
+}
+
+async fn get_job(data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    match data.backfill.get(&path).await {
+        Ok(Some(job)) => HttpResponse::Ok().json(job.view()),
+        Ok(None) => job_not_found(&path),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+/// Cancels a pending or running job. Events it already found stay in the event log.
+async fn cancel_job(data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    match data.backfill.cancel(&path).await {
+        Ok(Some(job)) => HttpResponse::Ok().json(job.view()),
+        Ok(None) => job_not_found(&path),
+        Err(err) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    }
+}
+
+/// Reloads the config file and the stored contracts, like SIGHUP does.
+async fn reload_config(data: web::Data<AppState>) -> HttpResponse {
+    match data.reloader.reload().await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(err @ ReloadError::Invalid(_)) => api_error(StatusCode::UNPROCESSABLE_ENTITY, "invalid_config", err),
+        Err(err @ ReloadError::Rejected(_)) => api_error(StatusCode::CONFLICT, "restart_required", err),
+        Err(err @ ReloadError::Failed(_)) => api_error(StatusCode::INTERNAL_SERVER_ERROR, "reload_failed", err),
+    }
+}
+
+/// Serves `route` on `method` requests to `path` for tokens carrying `scope`. Every
+/// method gets its own resource so each can require a different scope.
+fn scoped(path: &str, method: Method, scope: Scope, route: Route) -> impl HttpServiceFactory {
+    web::resource(path)
+        .guard(guard::Method(method))
+        .wrap(RequireScope(scope))
+        .route(route)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    let json_config = web::JsonConfig::default().error_handler(|err, _req| {
+        let response = api_error(StatusCode::BAD_REQUEST, "invalid_body", &err);
+        InternalError::from_response(err, response).into()
+    });
+
+    cfg.app_data(json_config)
+        .service(scoped("/contracts", Method::GET, Scope::Read, web::to(list_contracts)))
+        .service(scoped("/contracts", Method::POST, Scope::ContractsWrite, web::to(create_contract)))
+        .service(scoped("/contracts/{account_id}", Method::GET, Scope::Read, web::to(get_contract)))
+        .service(scoped("/contracts/{account_id}", Method::PATCH, Scope::ContractsWrite, web::to(update_contract)))
+        .service(scoped("/contracts/{account_id}", Method::DELETE, Scope::ContractsWrite, web::to(delete_contract)))
+        .service(scoped("/discovered", Method::GET, Scope::Read, web::to(list_discovered)))
+        .service(scoped("/discovered/{account_id}/promote", Method::POST, Scope::ContractsWrite, web::to(promote_discovered)))
+        .service(scoped("/deadletters", Method::GET, Scope::Read, web::to(list_dead_letters)))
+        .service(scoped("/deadletters/replay", Method::POST, Scope::DeadletterReplay, web::to(replay_dead_letters)))
+        .service(scoped("/webhooks", Method::GET, Scope::Read, web::to(list_webhooks)))
+        .service(scoped("/webhooks", Method::POST, Scope::WebhooksWrite, web::to(create_webhook)))
+        .service(scoped("/webhooks/{id}", Method::GET, Scope::Read, web::to(get_webhook)))
+        .service(scoped("/webhooks/{id}", Method::PATCH, Scope::WebhooksWrite, web::to(update_webhook)))
+        .service(scoped("/webhooks/{id}", Method::DELETE, Scope::WebhooksWrite, web::to(delete_webhook)))
+        .service(scoped("/webhooks/{id}/deliveries", Method::GET, Scope::Read, web::to(list_webhook_deliveries)))
+        .service(scoped("/jobs", Method::GET, Scope::Read, web::to(list_jobs)))
+        .service(scoped("/jobs", Method::POST, Scope::Backfill, web::to(create_job)))
+        .service(scoped("/jobs/{id}", Method::GET, Scope::Read, web::to(get_job)))
+        .service(scoped("/jobs/{id}", Method::DELETE, Scope::Backfill, web::to(cancel_job)))
+        .service(scoped("/config/reload", Method::POST, Scope::ConfigReload, web::to(reload_config)));
+}
@@ -0,0 +1,279 @@
+use std::fs;
+use std::future::{ ready, Future, Ready };
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ Context, Poll };
+use actix_web::{ web, Error, HttpMessage, error::InternalError, http::StatusCode };
+use actix_web::dev::{ Service, ServiceRequest, ServiceResponse, Transform };
+use serde::{ Serialize, Deserialize };
+use sha2::{ Digest, Sha256 };
+use subtle::ConstantTimeEq;
+
+use crate::admin_api::api_error;
+use crate::configs::AuthConfig;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    #[serde(rename = "read")]
+    Read,
+    #[serde(rename = "contracts:write")]
+    ContractsWrite,
+    #[serde(rename = "deadletter:replay")]
+    DeadletterReplay,
+    #[serde(rename = "backfill")]
+    Backfill,
+    #[serde(rename = "webhooks:write")]
+    WebhooksWrite,
+    #[serde(rename = "config:reload")]
+    ConfigReload,
+}
+
+impl Scope {
+    pub fn all() -> Vec<Scope> {
+        vec![Scope::Read, Scope::ContractsWrite, Scope::DeadletterReplay, Scope::Backfill, Scope::WebhooksWrite, Scope::ConfigReload]
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::ContractsWrite => "contracts:write",
+            Scope::DeadletterReplay => "deadletter:replay",
+            Scope::Backfill => "backfill",
+            Scope::WebhooksWrite => "webhooks:write",
+            Scope::ConfigReload => "config:reload",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        Scope::all().into_iter()
+            .find(|known| known.as_str() == scope)
+            .ok_or(format!("Unknown scope '{}', expected one of: read, contracts:write, deadletter:replay, backfill, webhooks:write, config:reload", scope))
+    }
+}
+
+/// A named admin token. Only the SHA-256 hash of the secret is kept.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ApiToken {
+    pub name: String,
+    /// Hex encoded SHA-256 of the token, as printed by `hash-token`
+    pub sha256: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// The token that authenticated the request, stored in the request extensions.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+pub struct ApiTokens {
+    tokens: Vec<(ApiToken, Vec<u8>)>,
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+impl ApiTokens {
+    pub fn new(tokens: Vec<ApiToken>) -> Result<Self, String> {
+        let tokens = tokens.into_iter().map(|token| {
+            let hash = hex::decode(&token.sha256).map_err(|_| format!("Token '{}' has a malformed sha256", token.name))?;
+            match hash.len() {
+                32 => Ok((token, hash)),
+                _ => Err(format!("Token '{}' has a malformed sha256", token.name)),
+            }
+        }).collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self { tokens })
+    }
+
+    /// Reads the tokens from `auth.tokens_file`. Without it, `auth.api_token` is the only
+    /// token and carries every scope.
+    pub fn load(config: &AuthConfig) -> Result<Self, String> {
+        if let Some(path) = &config.tokens_file {
+            let contents = fs::read_to_string(path).map_err(|err| format!("Could not read tokens file {}: {}", path.display(), err))?;
+            let tokens: Vec<ApiToken> = serde_json::from_str(&contents).map_err(|err| format!("Malformed tokens file {}: {}", path.display(), err))?;
+            return ApiTokens::new(tokens);
+        }
+
+        let api_token = config.api_token.as_ref().ok_or("auth.api_token (API_TOKEN) is not set".to_string())?;
+        ApiTokens::new(vec![ApiToken {
+            name: "default".to_string(),
+            sha256: hash_token(api_token),
+            scopes: Scope::all(),
+        }])
+    }
+
+    /// Finds the token matching the presented secret. Every stored hash is compared in
+    /// constant time so the response time does not reveal how close a guess was.
+    pub fn authenticate(&self, presented: &str) -> Option<&ApiToken> {
+        let presented_hash = Sha256::digest(presented.as_bytes());
+        let mut matched = None;
+
+        for (token, hash) in &self.tokens {
+            if bool::from(presented_hash.as_slice().ct_eq(hash.as_slice())) {
+                matched = Some(token);
+            }
+        }
+
+        matched
+    }
+}
+
+fn unauthorized(status: StatusCode, error: &str, message: &str) -> Error {
+    InternalError::from_response(message.to_string(), api_error(status, error, message)).into()
+}
+
+fn authorize(req: &ServiceRequest, scope: Scope) -> Result<AuthenticatedToken, Error> {
+    let tokens = req.app_data::<web::Data<ApiTokens>>().expect("ApiTokens are registered on the admin app");
+    let header = match req.headers().get("Authorization").and_then(|value| value.to_str().ok()) {
+        Some(header) => header,
+        None => return Err(unauthorized(StatusCode::UNAUTHORIZED, "unauthorized", "`Authorization: Bearer <token>` header is required")),
+    };
+    let presented = match header.strip_prefix("Bearer ") {
+        Some(presented) => presented.trim(),
+        None => return Err(unauthorized(StatusCode::UNAUTHORIZED, "unauthorized", "Authorization header must use the Bearer scheme")),
+    };
+
+    match tokens.authenticate(presented) {
+        Some(token) if token.scopes.contains(&scope) => Ok(AuthenticatedToken { name: token.name.clone(), scopes: token.scopes.clone() }),
+        Some(_) => Err(unauthorized(StatusCode::FORBIDDEN, "forbidden", &format!("Token lacks the `{}` scope", scope.as_str()))),
+        None => Err(unauthorized(StatusCode::UNAUTHORIZED, "unauthorized", "Unknown api token")),
+    }
+}
+
+/// Middleware rejecting requests without a bearer token carrying `scope`.
+pub struct RequireScope(pub Scope);
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequireScopeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireScopeMiddleware { service, scope: self.0 }))
+    }
+}
+
+pub struct RequireScopeMiddleware<S> {
+    service: S,
+    scope: Scope,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireScopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        match authorize(&req, self.scope) {
+            Ok(token) => {
+                req.extensions_mut().insert(token);
+                Box::pin(self.service.call(req))
+            }
+            Err(err) => Box::pin(async move { Err(err) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    /// SHA-256 of `test`
+    const TEST_HASH: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn token(name: &str, sha256: &str, scopes: Vec<Scope>) -> ApiToken {
+        ApiToken { name: name.to_string(), sha256: sha256.to_string(), scopes }
+    }
+
+    fn authorize_with(tokens: ApiTokens, header: Option<&str>, scope: Scope) -> Result<AuthenticatedToken, StatusCode> {
+        let mut req = TestRequest::default().app_data(web::Data::new(tokens));
+        if let Some(header) = header {
+            req = req.insert_header(("Authorization", header));
+        }
+
+        authorize(&req.to_srv_request(), scope).map_err(|err| err.as_response_error().status_code())
+    }
+
+    #[test]
+    fn hash_token_is_hex_sha256() {
+        assert_eq!(hash_token("test"), TEST_HASH);
+        assert_ne!(hash_token("test "), TEST_HASH);
+    }
+
+    #[test]
+    fn new_rejects_malformed_hashes() {
+        assert!(ApiTokens::new(vec![token("ops", "not hex", vec![])]).is_err());
+        assert!(ApiTokens::new(vec![token("ops", &TEST_HASH[..62], vec![])]).is_err());
+        assert!(ApiTokens::new(vec![token("ops", TEST_HASH, vec![])]).is_ok());
+    }
+
+    #[test]
+    fn authenticate_finds_the_matching_token() {
+        let tokens = ApiTokens::new(vec![
+            token("ops", TEST_HASH, vec![Scope::Read]),
+            token("ci", &hash_token("ci-secret"), vec![Scope::Backfill]),
+        ]).unwrap();
+
+        assert_eq!(tokens.authenticate("ci-secret").map(|token| token.name.as_str()), Some("ci"));
+        assert_eq!(tokens.authenticate("test").map(|token| token.name.as_str()), Some("ops"));
+        assert!(tokens.authenticate("guess").is_none());
+    }
+
+    #[test]
+    fn api_token_carries_every_scope() {
+        let tokens = ApiTokens::load(&AuthConfig { api_token: Some("test".to_string()), tokens_file: None }).unwrap();
+
+        assert_eq!(tokens.authenticate("test").map(|token| token.scopes.clone()), Some(Scope::all()));
+    }
+
+    #[test]
+    fn scopes_parse_from_their_names() {
+        for scope in Scope::all() {
+            assert_eq!(scope.as_str().parse::<Scope>(), Ok(scope));
+        }
+        assert!("admin".parse::<Scope>().is_err());
+    }
+
+    #[test]
+    fn authorize_checks_the_scope() {
+        let tokens = || ApiTokens::new(vec![token("reader", TEST_HASH, vec![Scope::Read])]).unwrap();
+
+        let authorized = authorize_with(tokens(), Some("Bearer test"), Scope::Read).unwrap();
+        assert_eq!(authorized.name, "reader");
+        assert_eq!(authorize_with(tokens(), Some("Bearer test"), Scope::ContractsWrite).unwrap_err(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn authorize_rejects_missing_and_unknown_tokens() {
+        let tokens = || ApiTokens::new(vec![token("reader", TEST_HASH, vec![Scope::Read])]).unwrap();
+
+        assert_eq!(authorize_with(tokens(), None, Scope::Read).unwrap_err(), StatusCode::UNAUTHORIZED);
+        assert_eq!(authorize_with(tokens(), Some("Basic dGVzdA=="), Scope::Read).unwrap_err(), StatusCode::UNAUTHORIZED);
+        assert_eq!(authorize_with(tokens(), Some("Bearer guess"), Scope::Read).unwrap_err(), StatusCode::UNAUTHORIZED);
+    }
+}
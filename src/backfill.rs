@@ -0,0 +1,412 @@
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use actix::Addr;
+use bson::{ Bson, doc, document::Document, oid::ObjectId };
+use mongodb::{ Database, Collection, options::FindOptions };
+use near_client::{ GetBlock, GetBlockError, GetExecutionOutcomesForBlock, ViewClientActor };
+use near_indexer::near_primitives::types::{ BlockId, BlockReference, SyncCheckpoint };
+use near_indexer::near_primitives::views::ExecutionStatusView;
+use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+use tokio_stream::StreamExt;
+use tracing::{ error, info, warn };
+
+use crate::Capacitor;
+use crate::contracts;
+use crate::event_log::EventLog;
+use crate::events::IndexedEvent;
+use crate::health::{ self, SyncStatus, VIEW_CLIENT_TIMEOUT };
+use crate::metrics;
+use crate::projections::Projections;
+use crate::sinks::SinkRouter;
+
+pub const JOBS_COLLECTION: &str = "jobs";
+
+/// Blocks processed between two progress updates, which is also how quickly a
+/// cancelled job notices it was cancelled.
+const PROGRESS_INTERVAL: u64 = 100;
+/// Attempts at a block before the job fails, for errors of the node or the event log
+/// that are expected to pass, such as a busy view client.
+const BLOCK_MAX_ATTEMPTS: u32 = 5;
+const BLOCK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Replays the history of a watched contract from `from_height` to `to_height` into the
+/// event log and the contract's sinks. Progress is stored after every few blocks so a
+/// restarted capacitor resumes where it stopped.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BackfillJob {
+    pub id: String,
+    /// Watched account or pattern whose events are backfilled
+    pub account_id: String,
+    pub from_height: u64,
+    pub to_height: u64,
+    /// Next block to process
+    pub current_height: u64,
+    pub events_found: u64,
+    pub blocks_per_second: f64,
+    pub status: JobStatus,
+    pub error: Option<String>,
+    pub created_by: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub finished_at: Option<String>,
+}
+
+impl BackfillJob {
+    pub fn new(account_id: String, from_height: u64, to_height: u64, created_by: &str) -> Self {
+        let now = chrono::Utc::now().to_rfc3339();
+
+        Self {
+            id: ObjectId::new().to_hex(),
+            account_id,
+            from_height,
+            to_height,
+            current_height: from_height,
+            events_found: 0,
+            blocks_per_second: 0.0,
+            status: JobStatus::Pending,
+            error: None,
+            created_by: created_by.to_string(),
+            created_at: now.clone(),
+            updated_at: now,
+            finished_at: None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status, JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled)
+    }
+
+    /// Seconds until the job is done at its current speed, while it runs.
+    pub fn eta_seconds(&self) -> Option<u64> {
+        match self.status == JobStatus::Running && self.blocks_per_second > 0.0 {
+            true => Some((self.to_height.saturating_sub(self.current_height) as f64 / self.blocks_per_second).ceil() as u64),
+            false => None,
+        }
+    }
+
+    /// The job as returned by the admin API, with its ETA.
+    pub fn view(&self) -> Value {
+        let mut view = serde_json::to_value(self).expect("Backfill job is always serializable");
+        view["eta_seconds"] = serde_json::to_value(self.eta_seconds()).unwrap_or(Value::Null);
+        view
+    }
+
+    pub fn to_document(&self) -> Document {
+        let value = serde_json::to_value(self).expect("Backfill job is always serializable");
+        let mut document = match Bson::from(value) {
+            Bson::Document(document) => document,
+            _ => unreachable!("Backfill job always serializes to an object"),
+        };
+
+        document.remove("id");
+        document.insert("_id", self.id.clone());
+        document.insert("kind", "backfill");
+        document
+    }
+
+    pub fn from_document(mut document: Document) -> Option<Self> {
+        let id = document.get_str("_id").ok()?.to_string();
+        document.remove("_id");
+        document.insert("id", id);
+
+        serde_json::from_value(Bson::Document(document).into_relaxed_extjson()).ok()
+    }
+}
+
+/// Runs backfill jobs in the background, next to but independent of live processing.
+#[derive(Clone)]
+pub struct Backfill {
+    database: Database,
+    view_client: Addr<ViewClientActor>,
+    capacitor_ins: Arc<Mutex<Capacitor>>,
+    event_log: EventLog,
+    sink_router: SinkRouter,
+    sync_status: SyncStatus,
+    projections: Option<Projections>,
+    blocks_per_second: u32,
+    /// Held while the projections are rebuilt, so jobs finishing together rebuild one after the other
+    rebuilding: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Backfill {
+    pub fn new(database: Database, view_client: Addr<ViewClientActor>, capacitor_ins: Arc<Mutex<Capacitor>>, event_log: EventLog, sink_router: SinkRouter, sync_status: SyncStatus, projections: Option<Projections>, blocks_per_second: u32) -> Self {
+        Self { database, view_client, capacitor_ins, event_log, sink_router, sync_status, projections, blocks_per_second, rebuilding: Arc::default() }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.database.collection(JOBS_COLLECTION)
+    }
+
+    /// Height backfills run up to when none is given: the last block processed live,
+    /// from where on the contract's events are already delivered.
+    pub async fn default_to_height(&self) -> Result<u64, String> {
+        match self.sync_status.last_height() {
+            Some(height) => Ok(height),
+            None => health::latest_node_height(&self.view_client).await,
+        }
+    }
+
+    /// Stores a new job and starts running it.
+    pub async fn start(&self, job: BackfillJob) -> Result<BackfillJob, mongodb::error::Error> {
+        self.collection().insert_one(job.to_document(), None).await?;
+        actix::spawn(self.clone().run(job.clone()));
+
+        Ok(job)
+    }
+
+    /// Restarts the jobs that were pending or running when the capacitor stopped.
+    pub async fn resume_unfinished(&self) -> Result<usize, mongodb::error::Error> {
+        let filter = doc! { "kind": "backfill", "status": { "$in": ["pending", "running"] } };
+        let mut cursor = self.collection().find(filter, None).await?;
+        let mut resumed = 0;
+
+        while let Some(document) = cursor.next().await {
+            if let Some(job) = BackfillJob::from_document(document?) {
+                info!(job_id = %job.id, contract = %job.account_id, height = job.current_height, "Resuming backfill");
+                actix::spawn(self.clone().run(job));
+                resumed += 1;
+            }
+        }
+
+        Ok(resumed)
+    }
+
+    pub async fn list(&self) -> Result<Vec<BackfillJob>, mongodb::error::Error> {
+        let options = FindOptions::builder().sort(doc! { "created_at": -1 }).build();
+        let mut cursor = self.collection().find(doc! { "kind": "backfill" }, options).await?;
+        let mut jobs = vec![];
+
+        while let Some(document) = cursor.next().await {
+            if let Some(job) = BackfillJob::from_document(document?) {
+                jobs.push(job);
+            }
+        }
+
+        Ok(jobs)
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<BackfillJob>, mongodb::error::Error> {
+        let document = self.collection().find_one(doc! { "_id": id }, None).await?;
+        Ok(document.and_then(BackfillJob::from_document))
+    }
+
+    /// Marks an unfinished job cancelled; its runner stops at its next progress update.
+    pub async fn cancel(&self, id: &str) -> Result<Option<BackfillJob>, mongodb::error::Error> {
+        self.collection().update_one(
+            doc! { "_id": id, "status": { "$in": ["pending", "running"] } },
+            doc! { "$set": { "status": "cancelled", "updated_at": chrono::Utc::now().to_rfc3339(), "finished_at": chrono::Utc::now().to_rfc3339() } },
+            None,
+        ).await?;
+
+        self.get(id).await
+    }
+
+    /// Stores the job's progress unless it was cancelled meanwhile. Returns whether it may continue.
+    async fn save_progress(&self, job: &mut BackfillJob) -> Result<bool, mongodb::error::Error> {
+        job.updated_at = chrono::Utc::now().to_rfc3339();
+        if job.is_finished() {
+            job.finished_at = Some(job.updated_at.clone());
+        }
+
+        let result = self.collection().replace_one(
+            doc! { "_id": job.id.clone(), "status": { "$in": ["pending", "running"] } },
+            job.to_document(),
+            None,
+        ).await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    async fn run(self, mut job: BackfillJob) {
+        job.status = JobStatus::Running;
+        let started_at = Instant::now();
+        let started_height = job.current_height;
+
+        if let Err(err) = self.check_history_available(job.current_height).await {
+            error!(job_id = %job.id, contract = %job.account_id, height = job.current_height, "Backfill failed: {}", err);
+            job.status = JobStatus::Failed;
+            job.error = Some(err);
+        }
+
+        while job.status == JobStatus::Running && job.current_height <= job.to_height {
+            // Backfilled blocks only run while the live loop has nothing to do
+            tokio::task::yield_now().await;
+            self.throttle(started_at, job.current_height - started_height).await;
+
+            match self.backfill_block_with_retries(&job, job.current_height).await {
+                Ok(found) => job.events_found += found as u64,
+                Err(err) => {
+                    error!(job_id = %job.id, contract = %job.account_id, height = job.current_height, "Backfill failed: {}", err);
+                    job.status = JobStatus::Failed;
+                    job.error = Some(err);
+                    break;
+                }
+            }
+            job.current_height += 1;
+
+            if (job.current_height - started_height) % PROGRESS_INTERVAL == 0 {
+                job.blocks_per_second = (job.current_height - started_height) as f64 / started_at.elapsed().as_secs_f64().max(0.001);
+
+                match self.save_progress(&mut job).await {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        info!(job_id = %job.id, contract = %job.account_id, height = job.current_height, "Backfill was cancelled");
+                        return;
+                    }
+                    Err(err) => error!(job_id = %job.id, "Failed to store backfill progress: {:?}", err),
+                }
+            }
+        }
+
+        if job.status == JobStatus::Running {
+            job.status = JobStatus::Completed;
+            info!(job_id = %job.id, contract = %job.account_id, events = job.events_found, "Backfill completed");
+        }
+        if let Err(err) = self.save_progress(&mut job).await {
+            error!(job_id = %job.id, "Failed to store the backfill result: {:?}", err);
+        }
+
+        if job.status == JobStatus::Completed && job.events_found > 0 {
+            self.rebuild_projections(&job).await;
+        }
+    }
+
+    /// Replays the whole event log into the projections, as backfilled events are older
+    /// than the state the projections already hold and cannot be applied on top of it.
+    async fn rebuild_projections(&self, job: &BackfillJob) {
+        let projections = match &self.projections {
+            Some(projections) => projections,
+            None => return,
+        };
+        let _rebuilding = self.rebuilding.lock().await;
+
+        info!(job_id = %job.id, contract = %job.account_id, "Rebuilding projections after backfill");
+        match projections.rebuild(&self.event_log).await {
+            Ok(replayed) => info!(job_id = %job.id, events = replayed, "Rebuilt projections"),
+            Err(err) => error!(job_id = %job.id, "Failed to rebuild projections after backfill, run rebuild-projections: {:?}", err),
+        }
+    }
+
+    /// Waits until `blocks_done` blocks are within the configured blocks per second, so a
+    /// backfill leaves the view client free for the live loop and the readiness checks.
+    async fn throttle(&self, started_at: Instant, blocks_done: u64) {
+        if self.blocks_per_second == 0 {
+            return;
+        }
+
+        let due = Duration::from_secs_f64(blocks_done as f64 / self.blocks_per_second as f64);
+        if let Some(wait) = due.checked_sub(started_at.elapsed()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Retries a failing block with a growing delay before giving up on it.
+    async fn backfill_block_with_retries(&self, job: &BackfillJob, block_height: u64) -> Result<usize, String> {
+        let mut attempt = 1;
+
+        loop {
+            match self.backfill_block(&job.account_id, block_height).await {
+                Err(err) if attempt < BLOCK_MAX_ATTEMPTS => {
+                    warn!(job_id = %job.id, contract = %job.account_id, height = block_height, attempt, "Backfill of a block failed, retrying: {}", err);
+                    tokio::time::sleep(BLOCK_RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Height of the oldest block the node still keeps. Below it a non-archival node has
+    /// garbage collected its history.
+    async fn earliest_available_height(&self) -> Result<u64, String> {
+        let _timer = metrics::VIEW_CLIENT_LATENCY.start_timer();
+
+        match self.view_client.send(GetBlock(BlockReference::SyncCheckpoint(SyncCheckpoint::EarliestAvailable))).timeout(VIEW_CLIENT_TIMEOUT).await {
+            Ok(Ok(block)) => Ok(block.header.height),
+            Ok(Err(err)) => Err(format!("{:?}", err)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// Fails when the node no longer keeps the block at `block_height`, so garbage collected
+    /// history is not mistaken for heights at which no block was produced.
+    async fn check_history_available(&self, block_height: u64) -> Result<(), String> {
+        let earliest_height = self.earliest_available_height().await?;
+
+        match block_height < earliest_height {
+            true => Err(format!("The node only keeps blocks from height {} on, backfilling from height {} needs an archival node", earliest_height, block_height)),
+            false => Ok(()),
+        }
+    }
+
+    /// Decodes the events `account_id` emitted in one block, appends them to the event log
+    /// and queues them on the contract's sinks' backfill queues. Returns how many were found.
+    async fn backfill_block(&self, account_id: &str, block_height: u64) -> Result<usize, String> {
+        let block_reference = BlockReference::BlockId(BlockId::Height(block_height));
+        let block_timer = metrics::VIEW_CLIENT_LATENCY.start_timer();
+        let block = match self.view_client.send(GetBlock(block_reference)).timeout(VIEW_CLIENT_TIMEOUT).await {
+            Ok(Ok(block)) => block,
+            // No block was produced at this height, unless the node dropped it since the job started
+            Ok(Err(GetBlockError::UnknownBlock { .. })) => return self.check_history_available(block_height).await.map(|_| 0),
+            Ok(Err(err)) => return Err(format!("{:?}", err)),
+            Err(err) => return Err(err.to_string()),
+        };
+        block_timer.observe_duration();
+
+        let outcomes_timer = metrics::VIEW_CLIENT_LATENCY.start_timer();
+        let outcomes = match self.view_client.send(GetExecutionOutcomesForBlock { block_hash: block.header.hash }).timeout(VIEW_CLIENT_TIMEOUT).await {
+            Ok(Ok(outcomes)) => outcomes,
+            Ok(Err(err)) => return Err(err),
+            Err(err) => return Err(err.to_string()),
+        };
+        outcomes_timer.observe_duration();
+
+        let mut shard_ids = outcomes.keys().cloned().collect::<Vec<_>>();
+        shard_ids.sort();
+
+        let mut block_events: Vec<(IndexedEvent, Option<Vec<String>>)> = vec![];
+        {
+            let capacitor_ins = self.capacitor_ins.lock().unwrap();
+
+            for shard_id in shard_ids {
+                for (outcome_index, outcome) in outcomes[&shard_id].iter().enumerate() {
+                    let executor_id = outcome.outcome.executor_id.as_str();
+                    if !contracts::matches_pattern(account_id, executor_id) || capacitor_ins.matching_contract(executor_id).is_none() {
+                        continue;
+                    }
+                    if !matches!(outcome.outcome.status, ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)) {
+                        continue;
+                    }
+
+                    let target_sinks = capacitor_ins.target_sinks(executor_id);
+                    for event in capacitor_ins.process_outcome(outcome, block_height, block.header.timestamp, shard_id, outcome_index as u64) {
+                        block_events.push((event, target_sinks.clone()));
+                    }
+                }
+            }
+        }
+
+        if block_events.is_empty() {
+            return Ok(0);
+        }
+
+        let events = block_events.iter().map(|(event, _)| event.clone()).collect::<Vec<_>>();
+        self.event_log.append(&events).await.map_err(|err| err.to_string())?;
+
+        let mut target_sinks = block_events.into_iter().map(|(_, target_sinks)| target_sinks);
+        self.sink_router.dispatch_backfill_block(&events, |_| target_sinks.next().flatten()).await;
+
+        Ok(events.len())
+    }
+}
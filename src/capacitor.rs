@@ -3,7 +3,10 @@ use near_indexer::near_primitives::{
         ExecutionOutcomeWithIdView, 
         ExecutionOutcomeView,
         ExecutionStatusView,
-        QueryRequest
+        QueryRequest,
+        ReceiptView,
+        ReceiptEnumView,
+        ActionView
     },
     types::{
         BlockReference,
@@ -22,9 +25,13 @@ use near_sdk::AccountId;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::{Base64VecU8};
 use std::vec::Vec;
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
+use std::sync::Mutex;
+use tracing::{ debug, info, warn };
 
-use crate::database;
+use crate::contracts::{ self, ContractUpdate, WatchedContract };
+use crate::events::{ self, IndexedEvent };
+use crate::metrics;
 
 
 /// Metadata for the NFT contract itself.
@@ -71,258 +78,329 @@ pub struct Token {
     pub approved_account_ids: Option<HashMap<AccountId, u64>>,
 }
 
+const ALLOWED_COLLECTION: &str = "allowed_account_ids";
+
+/// The stored watch list in `allowed_account_ids`. It only needs the database, so MongoDB
+/// is written without holding the capacitor the indexer processes blocks with.
+#[derive(Clone)]
+pub struct ContractStore {
+    collection: Collection<Document>,
+}
+
+impl ContractStore {
+    pub fn new(database: &Database) -> Self {
+        Self { collection: database.collection(ALLOWED_COLLECTION) }
+    }
+
+    /// Stores the contract unless the account is stored already. Returns whether it was
+    /// inserted, and the stored record, which is the existing one when it was not.
+    pub async fn insert(&self, contract: WatchedContract) -> Result<(bool, Option<WatchedContract>), String> {
+        let filter = contracts::account_id_filter(&contract.account_id);
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        // The unique index on `account_id` makes concurrent adds resolve to a single document
+        let result = self.collection.update_one(filter.clone(), doc! { "$setOnInsert": contract.to_document() }, options)
+            .await
+            .map_err(|err| format!("Failed to store account {}: {:?}", contract.account_id, err))?;
+
+        if result.upserted_id.is_some() {
+            return Ok((true, Some(contract)));
+        }
+
+        let stored = self.collection.find_one(filter, None).await.map_err(|err| err.to_string())?;
+        Ok((false, stored.and_then(WatchedContract::from_document)))
+    }
+
+    pub async fn update(&self, contract: &WatchedContract) -> Result<(), String> {
+        self.collection
+            .update_one(contracts::account_id_filter(&contract.account_id), doc! { "$set": contract.to_document() }, None)
+            .await
+            .map_err(|err| format!("Failed to update account {}: {:?}", contract.account_id, err))?;
+
+        Ok(())
+    }
+
+    pub async fn remove(&self, account_id: &str) -> Result<(), String> {
+        self.collection
+            .delete_many(contracts::account_id_filter(account_id), None)
+            .await
+            .map_err(|err| format!("Failed to remove account {}: {:?}", account_id, err))?;
+
+        Ok(())
+    }
+}
+
 pub struct Capacitor {
     capacitor_db: Database,
     database_client: Client,
-    allowed_ids: Vec<String>,
+    watched: HashMap<String, WatchedContract>,
+    /// Bumped by every write to the watch list, so a reload can tell whether the list it
+    /// read from MongoDB went stale before it was applied
+    watch_changes: u64,
+    /// Contracts deployed by watched factories that are watched but not stored yet
+    pending_deploys: HashSet<String>,
 }
 
 impl Capacitor {
-    pub fn new(database_client: Client, temp_allowed_ids: Vec<String>) -> Self {
+    pub fn new(database_client: Client, database_name: &str, temp_allowed_ids: Vec<String>) -> Self {
+        let watched = temp_allowed_ids.into_iter()
+            .map(|account_id| (account_id.clone(), WatchedContract::new(account_id, "config")))
+            .collect();
+
         Self {
-            capacitor_db: database_client.database("AstroMarket"),
-            allowed_ids: temp_allowed_ids,
+            capacitor_db: database_client.database(database_name),
+            watched,
             database_client,
+            watch_changes: 0,
+            pending_deploys: HashSet::new(),
         }
     }
 
+    pub fn database(&self) -> Database {
+        self.capacitor_db.clone()
+    }
+
     pub async fn load(&mut self) {
-		let allowed_collection: Collection<Document> = self.capacitor_db.collection("allowed_account_ids");
-		let mut cursor = allowed_collection.find(None, None).await.unwrap();
+        let stored = Self::read_watched(&self.capacitor_db, vec![]).await.unwrap_or_else(|err| panic!("{}", err));
+        self.watched.extend(stored);
+
+        info!(contracts = ?self.watched.keys().collect::<Vec<_>>(), "Listening for contracts");
+    }
+
+    /// Builds the watch list from the config contracts and the stored ones, which take
+    /// precedence, without touching the current list.
+    pub async fn read_watched(database: &Database, config_contracts: Vec<String>) -> Result<HashMap<String, WatchedContract>, String> {
+        let mut watched: HashMap<String, WatchedContract> = config_contracts.into_iter()
+            .map(|account_id| (account_id.clone(), WatchedContract::new(account_id, "config")))
+            .collect();
+
+        let mut cursor = database.collection::<Document>(ALLOWED_COLLECTION).find(None, None).await.map_err(|err| format!("Failed to read watched contracts: {:?}", err))?;
         while let Some(doc) = cursor.next().await {
-            let allowed_doc = doc.unwrap();
-            let account_id = allowed_doc.get("account_id").and_then(Bson::as_str).unwrap();
+            let allowed_doc = doc.map_err(|err| format!("Failed to read watched contracts: {:?}", err))?;
+            let contract = match WatchedContract::from_document(allowed_doc.clone()) {
+                Some(contract) => contract,
+                None => {
+                    warn!(document = ?allowed_doc, "Skipping malformed watched contract");
+                    continue;
+                }
+            };
+
+            watched.insert(contract.account_id.clone(), contract);
+        }
+
+        Ok(watched)
+    }
 
-            if !self.allowed_ids.contains(&account_id.to_string()) {
-                self.allowed_ids.push(account_id.to_string());
+    /// Swaps the whole watch list. Factory deployments watched since `watched` was read
+    /// are kept, they are persisted once their block is done.
+    pub fn replace_watched(&mut self, mut watched: HashMap<String, WatchedContract>) {
+        for (account_id, contract) in self.watched.drain() {
+            if self.pending_deploys.contains(&account_id) && !watched.contains_key(&account_id) {
+                watched.insert(account_id, contract);
             }
         }
+        self.watched = watched;
 
-        println!("📝 Listening for the following contracts: {:?}", self.allowed_ids);
+        info!(contracts = ?self.watched.keys().collect::<Vec<_>>(), "Listening for contracts");
     }
 
-    pub async fn add_account_id(&mut self, account_id: String) {
-        let allowed_collection: Collection<Document> = self.capacitor_db.collection("allowed_account_ids");
-        let doc = doc! {
-            "account_id": account_id.to_string(),
-        };
+    pub fn watch_changes(&self) -> u64 {
+        self.watch_changes
+    }
 
-        let mut cursor = allowed_collection.find(doc.clone(), None).await.unwrap();
+    /// Storage of the watch list, usable without holding the capacitor.
+    pub fn contract_store(&self) -> ContractStore {
+        ContractStore::new(&self.capacitor_db)
+    }
 
-        while let Some(doc) = cursor.next().await {
-            let allowed_doc = doc.unwrap();
-            let doc_account_id = allowed_doc.get("account_id").and_then(Bson::as_str).unwrap();
+    /// Stores a new watched contract and starts watching it. Returns `false` when the
+    /// account was already watched, in which case the stored record is kept as is.
+    /// Like the other writes, the capacitor is only locked around the watch list and
+    /// never across the MongoDB round-trip, which would stall the indexer.
+    pub async fn add_account_id(capacitor_ins: &Mutex<Capacitor>, contract: WatchedContract) -> Result<bool, String> {
+        let contract_store = capacitor_ins.lock().unwrap().contract_store();
+        let account_id = contract.account_id.clone();
+        let (inserted, stored) = contract_store.insert(contract).await?;
+        let mut capacitor_unwrapped = capacitor_ins.lock().unwrap();
+        capacitor_unwrapped.watch_changes += 1;
+        capacitor_unwrapped.pending_deploys.remove(&account_id);
+        if let Some(stored) = stored {
+            capacitor_unwrapped.watch(stored);
+        }
+
+        Ok(inserted)
+    }
+
+    /// Applies a partial update, returning the updated record or `None` for unknown accounts.
+    pub async fn update_contract(capacitor_ins: &Mutex<Capacitor>, account_id: &str, update: &ContractUpdate) -> Result<Option<WatchedContract>, String> {
+        let (contract_store, contract) = {
+            let capacitor_unwrapped = capacitor_ins.lock().unwrap();
+            (capacitor_unwrapped.contract_store(), capacitor_unwrapped.contract(account_id))
+        };
+        let mut contract = match contract {
+            Some(contract) => contract,
+            None => return Ok(None),
+        };
+        contract.apply(update);
 
-            if doc_account_id == account_id {
-                return ();
+        contract_store.update(&contract).await?;
+        let mut capacitor_unwrapped = capacitor_ins.lock().unwrap();
+        capacitor_unwrapped.watch_changes += 1;
+        capacitor_unwrapped.watch(contract.clone());
+        Ok(Some(contract))
+    }
+
+    /// Stops watching the account. Returns `false` when it was not watched.
+    pub async fn remove_contract(capacitor_ins: &Mutex<Capacitor>, account_id: &str) -> Result<bool, String> {
+        let contract_store = {
+            let capacitor_unwrapped = capacitor_ins.lock().unwrap();
+            if capacitor_unwrapped.contract(account_id).is_none() {
+                return Ok(false);
             }
+            capacitor_unwrapped.contract_store()
+        };
+
+        contract_store.remove(account_id).await?;
+        let mut capacitor_unwrapped = capacitor_ins.lock().unwrap();
+        capacitor_unwrapped.watch_changes += 1;
+        capacitor_unwrapped.watched.remove(account_id);
+        Ok(true)
+    }
+
+    pub fn contracts(&self) -> Vec<WatchedContract> {
+        let mut contracts: Vec<WatchedContract> = self.watched.values().cloned().collect();
+        contracts.sort_by(|a, b| a.account_id.cmp(&b.account_id));
+        contracts
+    }
+
+    pub fn contract(&self, account_id: &str) -> Option<WatchedContract> {
+        self.watched.get(account_id).cloned()
+    }
+
+    /// The watch list entry covering the account: its exact entry, or else the most
+    /// specific (longest) pattern matching it.
+    pub fn matching_contract(&self, account_id: &str) -> Option<&WatchedContract> {
+        if let Some(contract) = self.watched.get(account_id) {
+            return Some(contract);
         }
 
-        allowed_collection.insert_one(doc.clone(), None).await.unwrap();
-        self.allowed_ids.push(account_id.to_string());
+        self.watched.values()
+            .filter(|contract| contract.is_pattern() && contract.matches(account_id))
+            .max_by_key(|contract| contract.account_id.len())
     }
 
-    pub fn is_valid_receipt(&self, execution_outcome: &ExecutionOutcomeWithIdView) -> bool {
+    /// Sinks the contract's events are restricted to, `None` meaning all of them.
+    pub fn target_sinks(&self, account_id: &str) -> Option<Vec<String>> {
+        self.matching_contract(account_id).and_then(|contract| contract.sinks.clone())
+    }
+
+    /// Starts watching a contract right away, without persisting it. Used for records
+    /// just written through the `ContractStore`.
+    pub fn watch(&mut self, contract: WatchedContract) {
+        self.watched.insert(contract.account_id.clone(), contract);
+    }
+
+    /// Starts watching a contract a watched factory deployed mid-block. Until `add_account_id`
+    /// stores it, a reload keeps it in the watch list.
+    pub fn watch_deployed(&mut self, contract: WatchedContract) {
+        self.pending_deploys.insert(contract.account_id.clone());
+        self.watch(contract);
+    }
+
+    /// Detects a watched factory creating a sub-account and deploying a contract to it,
+    /// returning the record the new contract should be watched with.
+    pub fn detect_factory_deploy(&self, receipt: &ReceiptView, execution_outcome: &ExecutionOutcomeWithIdView, block_height: u64) -> Option<WatchedContract> {
+        match &execution_outcome.outcome.status {
+            ExecutionStatusView::SuccessValue(_) => (),
+            ExecutionStatusView::SuccessReceiptId(_) => (),
+            _ => return None
+        }
+
+        let factory = self.watched.get(receipt.predecessor_id.as_str())?;
+        let account_id = receipt.receiver_id.to_string();
+        if !factory.factory || !factory.enabled || self.watched.contains_key(&account_id) {
+            return None;
+        }
+        if !account_id.ends_with(&format!(".{}", factory.account_id)) {
+            return None;
+        }
+
+        let actions = match &receipt.receipt {
+            ReceiptEnumView::Action { actions, .. } => actions,
+            _ => return None,
+        };
+        let creates_account = actions.iter().any(|action| matches!(action, ActionView::CreateAccount));
+        let deploys_contract = actions.iter().any(|action| matches!(action, ActionView::DeployContract { .. }));
+
+        match creates_account && deploys_contract {
+            true => Some(factory.deployed_by(account_id, block_height)),
+            false => None,
+        }
+    }
+
+    pub fn is_valid_receipt(&self, execution_outcome: &ExecutionOutcomeWithIdView, block_height: u64) -> bool {
         match &execution_outcome.outcome.status {
             ExecutionStatusView::SuccessValue(_) => (),
             ExecutionStatusView::SuccessReceiptId(_) => (),
             _ => return false
         }
 
-        self.allowed_ids.contains(&execution_outcome.outcome.executor_id.to_string())
+        match self.matching_contract(execution_outcome.outcome.executor_id.as_str()) {
+            Some(contract) => contract.is_watched_at(block_height),
+            None => false,
+        }
     }
 
-    pub async fn process_outcome(&self, outcome: ExecutionOutcomeView, view_client: Addr<ViewClientActor>, public_api_root: String, signature_header: String) {
-        println!("🤖 Processing logs for {}", &outcome.executor_id);
-        // let normalized_database_name = "AstroMarket".to_string();
-        // let database = self.database_client.database(&normalized_database_name);
-
-        for log in &outcome.logs {
-            let logs_str;
-            if log.as_str().contains(&"EVENT_JSON:"){
-                println!("🤖 Processing logs for 1");
-                logs_str = log.as_str().replace("EVENT_JSON:",&"");
-                
-            } else {
-                logs_str = log.to_string();
-            }
-            let logs_parse = serde_json::from_str(logs_str.as_str());
-            
-            if logs_parse.is_err() {
-                println!("Skipping faulty log: {}", log.as_str());
-                continue;
-            }
+    /// Decodes the logs of a watched receipt into the events they describe.
+    pub fn process_outcome(&self, execution_outcome: &ExecutionOutcomeWithIdView, block_height: u64, block_timestamp: u64, shard_id: u64, outcome_index: u64) -> Vec<IndexedEvent> {
+        let mut indexed_events = vec![];
+        let outcome = &execution_outcome.outcome;
+        let contract_id = outcome.executor_id.as_str().to_string();
+        let receipt_id = execution_outcome.id.to_string();
+        debug!(height = block_height, contract = %contract_id, receipt_id = %receipt_id, "Processing logs");
 
-            let logs_parse_value: Option<Value> = logs_parse.unwrap();
-            if logs_parse_value.is_some() {
-                let parsed_logs: Value = serde_json::from_str(logs_str.as_str()).unwrap();
-                let log_type = &parsed_logs["event"].as_str().unwrap_or("None").to_string();
-                // let log_type = &parsed_logs["type"].as_str().unwrap_or("None").to_string();
-                //colleciton name
-                //let collection = database.collection(&"tokens".to_string());
-                let contract_id = &outcome.executor_id.as_str().to_string();
-                if log_type == "nft_mint" {
-                    let params = parsed_logs["data"].as_array().unwrap();
-                    for paras in params {
-                        let owner_id = &paras["owner_id"].as_str().unwrap().to_string();
-                        let tokenvalues = paras["token_ids"].as_array().cloned().unwrap();
-                        let tokenids : Vec<String> = tokenvalues.iter().map(|c| c.as_str().unwrap().to_string().clone()).collect();
-                        let _put_tokens_status = database::insert_minted_token_in_database(
-                            tokenids,
-                            contract_id.clone(),
-                            owner_id.clone(),
-                            signature_header.clone(),
-                            &public_api_root
-                        ).await;
-                    }
-                    
-			} else if log_type == "nft_transfer" {
-                    let params = parsed_logs["data"].as_array().unwrap();
-                    for paras in params {
-                        let tokenvalues = paras["token_ids"].as_array().cloned().unwrap();
-                        let old_owner_id = &paras["old_owner_id"].as_str().unwrap().to_string();
-                        let new_owner_id = &paras["new_owner_id"].as_str().unwrap().to_string();
-                        let tokenids : Vec<String> = tokenvalues.iter().map(|c| c.as_str().unwrap().to_string().clone()).collect();
-
-                        let _put_tokens_status = database::transfer_token_in_database(
-                            tokenids,
-                            contract_id.clone(),
-                            old_owner_id.clone(),
-                            new_owner_id.clone(),
-                            signature_header.clone(),
-                            &public_api_root
-                        ).await;
-                    }
-                } else if log_type == "add_market_data" {
-                    println!("🤖 Processing logs for add_market_data");
-                    let params = &parsed_logs["params"];
-                    let owner_id = params["owner_id"].as_str().unwrap().to_string();
-                    let approval_id = params["approval_id"].as_u64().unwrap();
-                    let nft_contract_id = params["nft_contract_id"].as_str().unwrap().to_string();
-                    let token_id = params["token_id"].as_str().unwrap_or("None").to_string();
-                    let ft_token_id = params["ft_token_id"].as_str().unwrap().to_string();
-                    let price = params["price"].as_str().unwrap().to_string();
-                    let started_at = params["started_at"].as_str().unwrap_or("0").to_string();
-                    let ended_at = params["ended_at"].as_str().unwrap_or("0").to_string();
-                    let is_auction = params["is_auction"].as_bool().unwrap_or(false);
-
-                    let _put_tokens_status = database::list_token_market_in_database(
-                        token_id,
-                        nft_contract_id,
-                        owner_id,
-                        approval_id,
-                        ft_token_id,
-                        price,
-                        started_at,
-                        ended_at,
-                        is_auction,
-                        signature_header.clone(),
-                        &public_api_root
-                    ).await;
-                } else if log_type == "update_market_data" {
-                    println!("🤖 Processing logs for update_market_data");
-                    let params = &parsed_logs["params"];
-                    let owner_id = params["owner_id"].as_str().unwrap().to_string();
-                    let nft_contract_id = params["nft_contract_id"].as_str().unwrap().to_string();
-                    let token_id = params["token_id"].as_str().unwrap_or("None").to_string();
-                    let ft_token_id = params["ft_token_id"].as_str().unwrap().to_string();
-                    let price = params["price"].as_str().unwrap().to_string();
-
-                    let _put_tokens_status = database::update_token_market_in_database(
-                        token_id,
-                        nft_contract_id,
-                        owner_id,
-                        ft_token_id,
-                        price,
-                        signature_header.clone(),
-                        &public_api_root
-                    ).await;
-                } else if log_type == "delete_market_data" {
-                    println!("🤖 Processing logs for delete_market_data");
-                    let params = &parsed_logs["params"];
-                    let owner_id = params["owner_id"].as_str().unwrap().to_string();
-                    let nft_contract_id = params["nft_contract_id"].as_str().unwrap().to_string();
-                    let token_id = params["token_id"].as_str().unwrap_or("None").to_string();
-
-                    let _put_tokens_status = database::delete_token_market_in_database(
-                        token_id,
-                        nft_contract_id,
-                        owner_id,
-                        signature_header.clone(),
-                        &public_api_root
-                    ).await;
-                } else if log_type == "add_bid" {
-                    println!("🤖 Processing logs for add_bid");
-                    let params = &parsed_logs["params"];
-                    let bidder_id = params["bidder_id"].as_str().unwrap().to_string();
-                    let nft_contract_id = params["nft_contract_id"].as_str().unwrap().to_string();
-                    let token_id = params["token_id"].as_str().unwrap_or("None").to_string();
-                    let ft_token_id = params["ft_token_id"].as_str().unwrap().to_string();
-                    let price = params["amount"].as_str().unwrap().to_string();
-
-                    let _put_tokens_status = database::bid_token_market_in_database(
-                        token_id,
-                        nft_contract_id,
-                        bidder_id,
-                        ft_token_id,
-                        price,
-                        signature_header.clone(),
-                        &public_api_root
-                    ).await;
-                } else if log_type == "add_offer" {
-                    println!("🤖 Processing logs for add_offer");
-                    let params = &parsed_logs["params"];
-                    let nft_contract_id = params["nft_contract_id"].as_str().unwrap().to_string();
-                    let token_id = params["token_id"].as_str().unwrap_or("None").to_string();
-                    let buyer_id = params["buyer_id"].as_str().unwrap().to_string();
-                    let ft_token_id = params["ft_token_id"].as_str().unwrap().to_string();
-                    let price = params["price"].as_str().unwrap().to_string();
-                    let _put_tokens_status = database::offer_token_market_in_database(
-                        token_id,
-                        nft_contract_id,
-                        buyer_id,
-                        ft_token_id,
-                        price,
-                        signature_header.clone(),
-                        &public_api_root
-                    ).await;
-                } else if log_type == "delete_offer" {
-                    println!("🤖 Processing logs for delete_offer");
-                    let params = &parsed_logs["params"];
-                    let nft_contract_id = params["nft_contract_id"].as_str().unwrap().to_string();
-                    let token_id = params["token_id"].as_str().unwrap_or("None").to_string();
-                    let buyer_id = params["buyer_id"].as_str().unwrap().to_string();
-                    let _put_tokens_status = database::unoffer_token_market_in_database(
-                        token_id,
-                        nft_contract_id,
-                        buyer_id,
-                        signature_header.clone(),
-                        &public_api_root
-                    ).await;
-                } else if log_type == "resolve_purchase" {
-                    println!("🤖 Processing logs for resolve_purchase");
-                    let params = &parsed_logs["params"];
-                    let owner_id = params["owner_id"].as_str().unwrap().to_string();
-                    let buyer_id = params["buyer_id"].as_str().unwrap().to_string();
-                    let is_offer = params["is_offer"].as_bool().unwrap_or(false);
-                    let nft_contract_id = params["nft_contract_id"].as_str().unwrap().to_string();
-                    let token_id = params["token_id"].as_str().unwrap_or("None").to_string();
-                    let ft_token_id = params["ft_token_id"].as_str().unwrap().to_string();
-                    let price = params["price"].as_str().unwrap().to_string();
-
-                    let _put_tokens_status = database::resolve_token_market_in_database(
-                        token_id,
-                        nft_contract_id,
-                        owner_id,
-                        ft_token_id,
-                        price,
-                        buyer_id,
-                        is_offer,
-                        signature_header.clone(),
-                        &public_api_root
-                    ).await;
+        for (log_index, log) in outcome.logs.iter().enumerate() {
+            let parsed_log = match events::parse_log(log.as_str()) {
+                Ok(parsed_log) => parsed_log,
+                Err(_) => {
+                    warn!(height = block_height, contract = %contract_id, receipt_id = %receipt_id, log = log.as_str(), "Skipping faulty log");
+                    metrics::PARSE_FAILURES.with_label_values(&[&contract_id]).inc();
+                    continue;
                 }
+            };
+
+            let payloads = match events::decode_payloads(&contract_id, &parsed_log) {
+                Ok(payloads) => payloads,
+                Err(err) => {
+                    warn!(height = block_height, contract = %contract_id, receipt_id = %receipt_id, event = %parsed_log["event"], log = log.as_str(), "Skipping malformed log: {}", err);
+                    metrics::PARSE_FAILURES.with_label_values(&[&contract_id]).inc();
+                    continue;
+                }
+            };
+
+            for (entry_index, payload) in payloads.into_iter().enumerate() {
+                if !self.matching_contract(&contract_id).map_or(true, |contract| contract.accepts_event(payload.event_type())) {
+                    continue;
+                }
+
+                debug!(height = block_height, contract = %contract_id, receipt_id = %receipt_id, event = payload.event_type(), "Decoded event");
+                metrics::EVENTS.with_label_values(&[&contract_id, payload.event_type()]).inc();
+                let event = IndexedEvent {
+                    event_id: format!("{}:{}:{}", receipt_id, log_index, entry_index),
+                    contract_id: contract_id.clone(),
+                    receipt_id: receipt_id.clone(),
+                    block_height,
+                    block_timestamp,
+                    shard_id,
+                    outcome_index,
+                    log_index: log_index as u64,
+                    payload,
+                };
+
+                indexed_events.push(event);
             }
         }
+
+        indexed_events
     }
 }
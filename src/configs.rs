@@ -1,9 +1,23 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
 use clap::Parser;
+use serde::Deserialize;
 use tracing_subscriber::EnvFilter;
 
+use crate::contracts;
+use crate::health::ReadinessThresholds;
+use crate::http_server::{ HttpConfig, ListenerConfig, TlsConfig };
+use crate::sinks::{ DeliveryMode, SinkConfig };
+
+/// Config file read when `--config` is not given, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "capacitor.toml";
+
 /// NEAR Indexer Example
 /// Watches for stream of blocks from the chain
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 #[clap(
     version,
     author,
@@ -16,23 +30,92 @@ pub(crate) struct Opts {
     /// Sets a custom config dir. Defaults to ~/.near/
     #[clap(short, long)]
     pub home_dir: Option<std::path::PathBuf>,
-    /// Enabled Indexer for Explorer debug level of logs
+    /// Capacitor config file. Defaults to ./capacitor.toml when it exists
+    #[clap(long, env = "CAPACITOR_CONFIG")]
+    pub config: Option<std::path::PathBuf>,
+    /// Where to start streaming blocks: latest_synced, from_interruption or block_height
+    #[clap(long)]
+    pub sync_mode: Option<SyncMode>,
+    /// Height to start from with `--sync-mode block_height`
+    #[clap(long)]
+    pub start_height: Option<u64>,
+    /// Raise the capacitor's own logs to debug level
     #[clap(long)]
     pub debug: bool,
     #[clap(subcommand)]
     pub subcmd: SubCommand,
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 pub(crate) enum SubCommand {
     /// Run NEAR Indexer Example. Start observe the network
     Run,
     /// Initialize necessary configs
     Init(InitConfigArgs),
+    /// Drop the ownership, listing and sales projections and rebuild them from the event log
+    RebuildProjections,
+    /// Read an admin token from stdin and print the hash to store in the tokens file
+    HashToken,
+    /// Validate the configuration and the node home dir without connecting to anything
+    CheckConfig,
+    /// Run every check of check-config, then check MongoDB and probe each sink
+    Doctor,
+    /// Manage the watched contracts stored in MongoDB without starting the node
+    Accounts(AccountsArgs),
+    /// Show how a log or a receipt outcome JSON is decoded, without delivering anything
+    Decode(DecodeArgs),
+}
+
+#[derive(Parser, Clone, Debug)]
+pub(crate) struct DecodeArgs {
+    /// Raw log string or receipt outcome JSON, read from stdin when omitted
+    pub input: Option<String>,
+    /// Contract the log was emitted by; a receipt outcome names its own
+    #[clap(long, default_value = "unknown")]
+    pub contract: String,
+}
+
+#[derive(Parser, Clone, Debug)]
+pub(crate) struct AccountsArgs {
+    #[clap(subcommand)]
+    pub command: AccountsCommand,
 }
 
+#[derive(Parser, Clone, Debug)]
+pub(crate) enum AccountsCommand {
+    /// List the watched contracts, including the ones from the config file
+    List,
+    /// Start watching an account or pattern
+    Add {
+        account_id: String,
+        #[clap(long)]
+        label: Option<String>,
+        /// Ignore the account's receipts below this height
+        #[clap(long)]
+        from_height: Option<u64>,
+    },
+    /// Stop watching an account or pattern
+    Remove { account_id: String },
+    /// Keep the account in the list but skip its receipts
+    Pause { account_id: String },
+    /// Process the account's receipts again
+    Resume { account_id: String },
+}
 
-#[derive(Parser, Debug)]
+impl AccountsCommand {
+    pub fn account_id(&self) -> Option<&str> {
+        match self {
+            AccountsCommand::List => None,
+            AccountsCommand::Add { account_id, .. } => Some(account_id),
+            AccountsCommand::Remove { account_id } => Some(account_id),
+            AccountsCommand::Pause { account_id } => Some(account_id),
+            AccountsCommand::Resume { account_id } => Some(account_id),
+        }
+    }
+}
+
+
+#[derive(Parser, Clone, Debug)]
 pub(crate) struct InitConfigArgs {
     /// chain/network id (localnet, testnet, devnet, betanet)
     #[clap(short, long)]
@@ -74,12 +157,528 @@ pub(crate) struct InitConfigArgs {
     pub boot_nodes: Option<String>,
 }
 
-pub(crate) fn init_logging() {
-    let env_filter = EnvFilter::new(
-        "tokio_reactor=info,near=info,near=error,stats=info,telemetry=info,indexer_example=info,indexer=info",
-    );
-    tracing_subscriber::fmt::Subscriber::builder()
+/// Where the indexer starts streaming blocks from.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    LatestSynced,
+    FromInterruption,
+    BlockHeight,
+}
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.replace('-', "_").to_lowercase().as_str() {
+            "latest_synced" => Ok(SyncMode::LatestSynced),
+            "from_interruption" => Ok(SyncMode::FromInterruption),
+            "block_height" => Ok(SyncMode::BlockHeight),
+            _ => Err(format!("Unknown sync mode '{}', expected one of: latest_synced, from_interruption, block_height", mode)),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// NEAR node home dir, defaults to ~/.near/
+    pub home_dir: Option<PathBuf>,
+    /// Chain the home dir's genesis must belong to, e.g. `mainnet`; checked by `check-config`
+    pub chain_id: Option<String>,
+    pub sync_mode: SyncMode,
+    /// Height to start from with `sync_mode = "block_height"`
+    pub start_height: Option<u64>,
+    /// Wait for the node to be fully synced before streaming blocks
+    pub await_synced: bool,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            home_dir: None,
+            chain_id: None,
+            sync_mode: SyncMode::LatestSynced,
+            start_height: None,
+            await_synced: true,
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn home_dir(&self) -> PathBuf {
+        self.home_dir.clone().unwrap_or(PathBuf::from(near_indexer::get_default_home()))
+    }
+
+    pub fn indexer_config(&self) -> near_indexer::IndexerConfig {
+        let sync_mode = match self.sync_mode {
+            SyncMode::LatestSynced => near_indexer::SyncModeEnum::LatestSynced,
+            SyncMode::FromInterruption => near_indexer::SyncModeEnum::FromInterruption,
+            SyncMode::BlockHeight => near_indexer::SyncModeEnum::BlockHeight(self.start_height.expect("start_height is validated with the block_height sync mode")),
+        };
+        let await_for_node_synced = match self.await_synced {
+            true => near_indexer::AwaitForNodeSyncedEnum::WaitForFullSync,
+            false => near_indexer::AwaitForNodeSyncedEnum::StreamWhileSyncing,
+        };
+
+        near_indexer::IndexerConfig {
+            home_dir: self.home_dir(),
+            sync_mode,
+            await_for_node_synced,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MongoConfig {
+    pub uri: Option<String>,
+    pub database: String,
+}
+
+impl Default for MongoConfig {
+    fn default() -> Self {
+        Self {
+            uri: None,
+            database: "AstroMarket".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Admin token carrying every scope, also sent as the signature of HTTP sinks
+    pub api_token: Option<String>,
+    /// JSON file listing hashed admin tokens and their scopes, replacing `api_token` for the admin API
+    pub tokens_file: Option<PathBuf>,
+}
+
+/// How events are delivered when no `[[sinks]]` are configured.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeliveryConfig {
+    pub mode: DeliveryMode,
+    /// Endpoint every event is POSTed to in the `http` and `both` modes
+    pub public_api: Option<String>,
+    /// JSON file listing the sinks, used when no `[[sinks]]` are configured
+    pub sinks_file: Option<PathBuf>,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        Self {
+            mode: DeliveryMode::Http,
+            public_api: None,
+            sinks_file: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexingConfig {
+    /// Keep the ownership, listing and sales projections up to date. Off by default as
+    /// they are written in transactions, which need MongoDB to run as a replica set
+    pub projections: bool,
+    /// Record unwatched contracts emitting NEP-171 events
+    pub discovery: bool,
+    /// Blocks each backfill job reads from the node per second, 0 for no limit
+    pub backfill_blocks_per_second: u32,
+}
+
+impl Default for IndexingConfig {
+    fn default() -> Self {
+        Self {
+            projections: false,
+            discovery: false,
+            backfill_blocks_per_second: 20,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    Pretty,
+    /// One JSON object per line, with the structured fields as keys
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format '{}', expected one of: pretty, json", format)),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `tracing` filter directives, replaced by `RUST_LOG` when set
+    pub filter: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            filter: "tokio_reactor=info,near=info,near=error,stats=info,telemetry=info,indexer_example=info,indexer=info".to_string(),
+            format: LogFormat::Pretty,
+        }
+    }
+}
+
+/// Everything the capacitor is configured with. Built from the defaults, then the
+/// TOML config file, then environment variables and finally the command line flags.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub node: NodeConfig,
+    pub mongodb: MongoConfig,
+    pub auth: AuthConfig,
+    pub http: HttpConfig,
+    pub delivery: DeliveryConfig,
+    /// Sinks events are delivered to, replacing `[delivery]` when given
+    pub sinks: Option<Vec<SinkConfig>>,
+    /// Accounts or patterns watched on top of the ones stored in MongoDB
+    pub contracts: Vec<String>,
+    pub indexing: IndexingConfig,
+    pub readiness: ReadinessThresholds,
+    pub logging: LoggingConfig,
+}
+
+/// Environment variables the config is layered with, passed in rather than read
+/// from the process so loading stays testable.
+type EnvVars = HashMap<String, String>;
+
+/// The process environment; variables that are not valid unicode are left out, as
+/// `env::var` treated them as unset.
+fn process_env() -> EnvVars {
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect()
+}
+
+/// Parses environment variable `name` when set, recording a problem when it is malformed.
+fn env_parse<T: FromStr>(vars: &EnvVars, name: &str, problems: &mut Vec<String>) -> Option<T> {
+    let value = vars.get(name)?;
+
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            problems.push(format!("{} has an invalid value '{}'", name, value));
+            None
+        }
+    }
+}
+
+/// Applies `<PREFIX>_BIND_ADDRESS`, `<PREFIX>_PORT`, `<PREFIX>_TLS_CERT` and `<PREFIX>_TLS_KEY`.
+fn apply_listener_env(vars: &EnvVars, listener: &mut ListenerConfig, prefix: &str, problems: &mut Vec<String>) {
+    if let Some(address) = vars.get(&format!("{}_BIND_ADDRESS", prefix)).cloned() {
+        listener.address = address;
+    }
+    if let Some(port) = env_parse(vars, &format!("{}_PORT", prefix), problems) {
+        listener.port = port;
+    }
+
+    match (vars.get(&format!("{}_TLS_CERT", prefix)).cloned(), vars.get(&format!("{}_TLS_KEY", prefix)).cloned()) {
+        (Some(cert_path), Some(key_path)) => listener.tls = Some(TlsConfig { cert_path, key_path }),
+        (None, None) => (),
+        _ => problems.push(format!("{}_TLS_CERT and {}_TLS_KEY must be set together", prefix, prefix)),
+    }
+}
+
+impl Config {
+    /// Loads and validates the configuration, reporting every problem found at once.
+    pub(crate) fn load(opts: &Opts) -> Result<Self, String> {
+        let mut config = match &opts.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+
+        let mut problems = config.apply_env(&process_env());
+        config.apply_opts(opts);
+        problems.extend(config.validate(matches!(opts.subcmd, SubCommand::Run | SubCommand::CheckConfig | SubCommand::Doctor)));
+
+        match problems.is_empty() {
+            true => Ok(config),
+            false => Err(format!("Invalid configuration:\n  - {}", problems.join("\n  - "))),
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|err| format!("Could not read config file {}: {}", path.display(), err))?;
+        toml::from_str(&contents).map_err(|err| format!("Malformed config file {}: {}", path.display(), err))
+    }
+
+    /// Overrides the file with the environment variables the capacitor has always read.
+    fn apply_env(&mut self, vars: &EnvVars) -> Vec<String> {
+        let mut problems = vec![];
+
+        if let Some(chain_id) = vars.get("CHAIN_ID").cloned() {
+            self.node.chain_id = Some(chain_id);
+        }
+        if let Some(sync_mode) = env_parse(vars, "SYNC_MODE", &mut problems) {
+            self.node.sync_mode = sync_mode;
+        }
+        if let Some(start_height) = env_parse(vars, "START_HEIGHT", &mut problems) {
+            self.node.start_height = Some(start_height);
+        }
+
+        if let Some(uri) = vars.get("MONGODB_URI").cloned() {
+            self.mongodb.uri = Some(uri);
+        }
+        if let Some(database) = vars.get("MONGODB_DATABASE").cloned() {
+            self.mongodb.database = database;
+        }
+
+        if let Some(api_token) = vars.get("API_TOKEN").cloned() {
+            self.auth.api_token = Some(api_token);
+        }
+        if let Some(tokens_file) = vars.get("ADMIN_TOKENS_FILE").cloned() {
+            self.auth.tokens_file = Some(PathBuf::from(tokens_file));
+        }
+
+        apply_listener_env(vars, &mut self.http.admin, "ADMIN", &mut problems);
+        if self.http.public.is_none() && vars.contains_key("PUBLIC_PORT") {
+            self.http.public = Some(ListenerConfig::default());
+        }
+        if let Some(public) = &mut self.http.public {
+            apply_listener_env(vars, public, "PUBLIC", &mut problems);
+        }
+        if let Some(workers) = env_parse(vars, "HTTP_WORKERS", &mut problems) {
+            self.http.workers = Some(workers);
+        }
+
+        if let Some(mode) = env_parse(vars, "DELIVERY_MODE", &mut problems) {
+            self.delivery.mode = mode;
+        }
+        if let Some(public_api) = vars.get("PUBLIC_API").cloned() {
+            self.delivery.public_api = Some(public_api);
+        }
+        if let Some(sinks_file) = vars.get("SINKS_CONFIG").cloned() {
+            self.delivery.sinks_file = Some(PathBuf::from(sinks_file));
+        }
+
+        if let Some(projections) = env_parse(vars, "PROJECTIONS_ENABLED", &mut problems) {
+            self.indexing.projections = projections;
+        }
+        if let Some(discovery) = env_parse(vars, "DISCOVERY_ENABLED", &mut problems) {
+            self.indexing.discovery = discovery;
+        }
+        if let Some(blocks_per_second) = env_parse(vars, "BACKFILL_BLOCKS_PER_SECOND", &mut problems) {
+            self.indexing.backfill_blocks_per_second = blocks_per_second;
+        }
+
+        if let Some(max_lag_blocks) = env_parse(vars, "READY_MAX_LAG_BLOCKS", &mut problems) {
+            self.readiness.max_lag_blocks = max_lag_blocks;
+        }
+        if let Some(max_block_age_secs) = env_parse(vars, "READY_MAX_BLOCK_AGE_SECS", &mut problems) {
+            self.readiness.max_block_age_secs = max_block_age_secs;
+        }
+        if let Some(max_outbox_depth) = env_parse(vars, "READY_MAX_OUTBOX_DEPTH", &mut problems) {
+            self.readiness.max_outbox_depth = max_outbox_depth;
+        }
+        if let Some(max_delivery_idle_secs) = env_parse(vars, "READY_MAX_DELIVERY_IDLE_SECS", &mut problems) {
+            self.readiness.max_delivery_idle_secs = Some(max_delivery_idle_secs);
+        }
+
+        if let Some(filter) = vars.get("RUST_LOG").cloned() {
+            self.logging.filter = filter;
+        }
+        if let Some(format) = env_parse(vars, "LOG_FORMAT", &mut problems) {
+            self.logging.format = format;
+        }
+
+        problems
+    }
+
+    fn apply_opts(&mut self, opts: &Opts) {
+        if let Some(home_dir) = &opts.home_dir {
+            self.node.home_dir = Some(home_dir.clone());
+        }
+        if let Some(sync_mode) = opts.sync_mode {
+            self.node.sync_mode = sync_mode;
+        }
+        if let Some(start_height) = opts.start_height {
+            self.node.start_height = Some(start_height);
+        }
+    }
+
+    /// Settings only needed to run the indexer are checked when `running`, so the other
+    /// subcommands work without them.
+    fn validate(&self, running: bool) -> Vec<String> {
+        let mut problems = vec![];
+
+        if self.node.sync_mode == SyncMode::BlockHeight && self.node.start_height.is_none() {
+            problems.push("node.start_height (START_HEIGHT, --start-height) is required with the block_height sync mode".to_string());
+        }
+        if running && self.mongodb.uri.is_none() {
+            problems.push("mongodb.uri (MONGODB_URI) is not set".to_string());
+        }
+        if running && self.auth.api_token.is_none() {
+            problems.push("auth.api_token (API_TOKEN) is not set".to_string());
+        }
+        if running && self.sinks.is_none() && self.delivery.sinks_file.is_none() && self.delivery.mode != DeliveryMode::MongoDb && self.delivery.public_api.is_none() {
+            problems.push("delivery.public_api (PUBLIC_API) is required unless sinks are configured or the delivery mode is mongodb".to_string());
+        }
+        if self.http.public.as_ref().map_or(false, |public| public.port == 0) {
+            problems.push("http.public.port (PUBLIC_PORT) must be set for the public listener".to_string());
+        }
+        for account_id in &self.contracts {
+            if let Err(err) = contracts::validate_account_id(account_id) {
+                problems.push(format!("contracts: {}", err));
+            }
+        }
+        if let Err(err) = EnvFilter::try_new(&self.logging.filter) {
+            problems.push(format!("logging.filter is invalid: {}", err));
+        }
+
+        problems
+    }
+
+    /// Sections that differ in `new` but can only change on a restart. Sinks, delivery
+    /// and contracts are the ones a reload applies.
+    pub fn fixed_changes(&self, new: &Config) -> Vec<&'static str> {
+        let sections = [
+            ("node", self.node != new.node),
+            ("mongodb", self.mongodb != new.mongodb),
+            ("auth", self.auth != new.auth),
+            ("http", self.http != new.http),
+            ("indexing", self.indexing != new.indexing),
+            ("readiness", self.readiness != new.readiness),
+            ("logging", self.logging != new.logging),
+        ];
+
+        sections.iter().filter(|(_, changed)| *changed).map(|(section, _)| *section).collect()
+    }
+}
+
+/// `--debug` raises the capacitor's own targets to debug on top of the configured filter.
+pub(crate) fn init_logging(config: &LoggingConfig, debug: bool) {
+    let mut env_filter = EnvFilter::new(&config.filter);
+    if debug {
+        env_filter = env_filter.add_directive("indexer_example=debug".parse().expect("Directive is valid"));
+    }
+
+    let subscriber = tracing_subscriber::fmt::Subscriber::builder()
         .with_env_filter(env_filter)
-        .with_writer(std::io::stderr)
-        .init();
-}
\ No newline at end of file
+        .with_writer(std::io::stderr);
+
+    match config.format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Config {
+        toml::from_str(contents).unwrap()
+    }
+
+    fn opts(args: &[&str]) -> Opts {
+        Opts::parse_from(std::iter::once("indexer").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn file_overrides_defaults_per_field() {
+        let config = parse(r#"
+            contracts = ["nft.near"]
+
+            [mongodb]
+            uri = "mongodb://localhost:27017"
+
+            [node]
+            sync_mode = "from_interruption"
+        "#);
+
+        assert_eq!(config.mongodb.uri.as_deref(), Some("mongodb://localhost:27017"));
+        assert_eq!(config.mongodb.database, MongoConfig::default().database);
+        assert_eq!(config.node.sync_mode, SyncMode::FromInterruption);
+        assert!(config.node.await_synced);
+        assert_eq!(config.contracts, vec!["nft.near"]);
+        assert_eq!(config.indexing, IndexingConfig::default());
+    }
+
+    #[test]
+    fn file_rejects_unknown_keys() {
+        assert!(toml::from_str::<Config>("[mongodb]\nurl = \"mongodb://localhost\"").is_err());
+        assert!(toml::from_str::<Config>("watched = []").is_err());
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let mut config = parse("[node]\nsync_mode = \"latest_synced\"\nstart_height = 1");
+
+        config.apply_opts(&opts(&["--sync-mode", "block_height", "--start-height", "42", "run"]));
+
+        assert_eq!(config.node.sync_mode, SyncMode::BlockHeight);
+        assert_eq!(config.node.start_height, Some(42));
+    }
+
+    #[test]
+    fn environment_overrides_the_file_and_reports_malformed_values() {
+        let mut config = parse("[mongodb]\ndatabase = \"FromFile\"");
+        let vars = vec![("MONGODB_DATABASE", "FromEnv"), ("READY_MAX_LAG_BLOCKS", "many")].into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        let problems = config.apply_env(&vars);
+
+        assert_eq!(config.mongodb.database, "FromEnv");
+        assert_eq!(problems, vec!["READY_MAX_LAG_BLOCKS has an invalid value 'many'"]);
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let config = parse(r#"
+            contracts = ["nft.near", "Not Valid"]
+
+            [node]
+            sync_mode = "block_height"
+
+            [http.public]
+            address = "0.0.0.0"
+        "#);
+
+        let problems = config.validate(true);
+
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        assert!(problems[0].starts_with("node.start_height"));
+        assert!(problems[1].starts_with("mongodb.uri"));
+        assert!(problems[2].starts_with("auth.api_token"));
+        assert!(problems[3].starts_with("delivery.public_api"));
+        assert!(problems[4].starts_with("http.public.port"));
+        assert!(problems[5].starts_with("contracts: 'Not Valid'"));
+    }
+
+    #[test]
+    fn validate_only_requires_running_settings_when_running() {
+        let config = Config::default();
+
+        assert!(config.validate(false).is_empty());
+        assert_eq!(config.validate(true).len(), 3);
+    }
+
+    #[test]
+    fn fixed_changes_ignores_reloadable_sections() {
+        let current = Config::default();
+        let mut new = parse("contracts = [\"nft.near\"]\n\n[delivery]\nmode = \"mongodb\"");
+        assert!(current.fixed_changes(&new).is_empty());
+
+        new.indexing.projections = true;
+        new.logging.filter = "info".to_string();
+        assert_eq!(current.fixed_changes(&new), vec!["indexing", "logging"]);
+    }
+}
@@ -0,0 +1,293 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+use near_indexer::near_primitives::types::AccountId;
+use bson::{ Bson, doc, document::Document };
+use serde::{ Serialize, Deserialize };
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContractKind {
+    Nft,
+    Marketplace,
+    Ft,
+}
+
+impl Default for ContractKind {
+    fn default() -> Self {
+        ContractKind::Nft
+    }
+}
+
+impl FromStr for ContractKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind.to_lowercase().as_str() {
+            "nft" => Ok(ContractKind::Nft),
+            "marketplace" => Ok(ContractKind::Marketplace),
+            "ft" => Ok(ContractKind::Ft),
+            _ => Err(format!("Unknown contract kind '{}', expected one of: nft, marketplace, ft", kind)),
+        }
+    }
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// A contract the capacitor watches, as stored in `allowed_account_ids`.
+/// Documents written before these fields existed only carry `account_id`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WatchedContract {
+    pub account_id: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Paused contracts stay in the list but none of their receipts are processed
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub kind: ContractKind,
+    /// Receipts below this height are ignored
+    #[serde(default)]
+    pub start_height: u64,
+    /// RFC 3339 timestamp
+    #[serde(default)]
+    pub added_at: Option<String>,
+    #[serde(default)]
+    pub added_by: Option<String>,
+    /// Names of the sinks this contract's events go to, all sinks when omitted
+    #[serde(default)]
+    pub sinks: Option<Vec<String>>,
+    /// Event types that are indexed for this contract, all of them when omitted
+    #[serde(default)]
+    pub events: Option<Vec<String>>,
+    /// Automatically watch every sub-account this account creates and deploys a contract to
+    #[serde(default)]
+    pub factory: bool,
+}
+
+impl WatchedContract {
+    pub fn new(account_id: String, added_by: &str) -> Self {
+        Self {
+            account_id,
+            label: None,
+            enabled: true,
+            kind: ContractKind::default(),
+            start_height: 0,
+            added_at: Some(chrono::Utc::now().to_rfc3339()),
+            added_by: Some(added_by.to_string()),
+            sinks: None,
+            events: None,
+            factory: false,
+        }
+    }
+
+    /// Watch list entries containing `*` match account ids by pattern, e.g. `*.astro-factory.near`.
+    pub fn is_pattern(&self) -> bool {
+        self.account_id.contains('*')
+    }
+
+    pub fn matches(&self, account_id: &str) -> bool {
+        match self.is_pattern() {
+            true => matches_pattern(&self.account_id, account_id),
+            false => self.account_id == account_id,
+        }
+    }
+
+    /// Record for a contract a watched factory deployed at `block_height`. It inherits
+    /// the factory's kind, routing and event allowlist.
+    pub fn deployed_by(&self, account_id: String, block_height: u64) -> Self {
+        let mut contract = WatchedContract::new(account_id, &format!("factory:{}", self.account_id));
+        contract.kind = self.kind;
+        contract.start_height = block_height;
+        contract.sinks = self.sinks.clone();
+        contract.events = self.events.clone();
+        contract
+    }
+
+    /// One line description for the command line, e.g. `nft, paused, from block 120`.
+    pub fn describe(&self) -> String {
+        let mut details = vec![format!("{:?}", self.kind).to_lowercase()];
+        if let Some(label) = &self.label {
+            details.push(format!("\"{}\"", label));
+        }
+        if !self.enabled {
+            details.push("paused".to_string());
+        }
+        if self.factory {
+            details.push("factory".to_string());
+        }
+        if self.start_height > 0 {
+            details.push(format!("from block {}", self.start_height));
+        }
+        if let Some(events) = &self.events {
+            details.push(format!("events {}", events.join(",")));
+        }
+        if let Some(sinks) = &self.sinks {
+            details.push(format!("sinks {}", sinks.join(",")));
+        }
+        details.join(", ")
+    }
+
+    pub fn to_document(&self) -> Document {
+        let value = serde_json::to_value(self).expect("Watched contract is always serializable");
+
+        match Bson::from(value) {
+            Bson::Document(document) => document,
+            _ => unreachable!("Watched contract always serializes to an object"),
+        }
+    }
+
+    pub fn from_document(mut document: Document) -> Option<Self> {
+        document.remove("_id");
+        serde_json::from_value(Bson::Document(document).into_relaxed_extjson()).ok()
+    }
+
+    pub fn is_watched_at(&self, block_height: u64) -> bool {
+        self.enabled && block_height >= self.start_height
+    }
+
+    pub fn accepts_event(&self, event_type: &str) -> bool {
+        match &self.events {
+            Some(events) => events.iter().any(|allowed| allowed == event_type),
+            None => true,
+        }
+    }
+
+    pub fn apply(&mut self, update: &ContractUpdate) {
+        if let Some(label) = &update.label {
+            self.label = Some(label.clone());
+        }
+        if let Some(enabled) = update.enabled {
+            self.enabled = enabled;
+        }
+        if let Some(kind) = update.kind {
+            self.kind = kind;
+        }
+        if let Some(start_height) = update.start_height {
+            self.start_height = start_height;
+        }
+        if let Some(sinks) = &update.sinks {
+            self.sinks = Some(sinks.clone());
+        }
+        if let Some(events) = &update.events {
+            self.events = Some(events.clone());
+        }
+        if let Some(factory) = update.factory {
+            self.factory = factory;
+        }
+    }
+}
+
+/// Partial update of a watched contract, only the given fields change.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ContractUpdate {
+    pub label: Option<String>,
+    pub enabled: Option<bool>,
+    pub kind: Option<ContractKind>,
+    pub start_height: Option<u64>,
+    pub sinks: Option<Vec<String>>,
+    pub events: Option<Vec<String>>,
+    pub factory: Option<bool>,
+}
+
+/// Body of a request to start watching a contract.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NewContract {
+    pub account_id: String,
+    #[serde(flatten)]
+    pub fields: ContractUpdate,
+}
+
+impl NewContract {
+    pub fn into_contract(self, added_by: &str) -> WatchedContract {
+        let mut contract = WatchedContract::new(self.account_id, added_by);
+        contract.apply(&self.fields);
+        contract
+    }
+}
+
+/// Checks an account id, or a watch pattern, against NEAR's account id rules.
+/// In patterns every `*` is checked as if it were a valid account id character.
+pub fn validate_account_id(account_id: &str) -> Result<(), String> {
+    let candidate = account_id.replace('*', "a");
+
+    AccountId::try_from(candidate)
+        .map(|_| ())
+        .map_err(|err| format!("'{}' is not a valid account id: {}", account_id, err))
+}
+
+/// Glob match where `*` stands for any run of characters.
+pub fn matches_pattern(pattern: &str, account_id: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+
+    if parts.len() == 1 {
+        return pattern == account_id;
+    }
+    if !account_id.starts_with(first) || !account_id[first.len()..].ends_with(last) {
+        return false;
+    }
+
+    let mut remaining = &account_id[first.len()..account_id.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match remaining.find(part) {
+            Some(position) => remaining = &remaining[position + part.len()..],
+            None => return false,
+        }
+    }
+
+    true
+}
+
+pub fn account_id_filter(account_id: &str) -> Document {
+    doc! { "account_id": account_id }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_pattern_without_wildcard_is_exact() {
+        assert!(matches_pattern("nft.near", "nft.near"));
+        assert!(!matches_pattern("nft.near", "nft.near.x"));
+        assert!(!matches_pattern("nft.near", "xnft.near"));
+    }
+
+    #[test]
+    fn matches_pattern_with_one_wildcard() {
+        assert!(matches_pattern("*.paras.near", "x.paras.near"));
+        assert!(matches_pattern("nft-*", "nft-"));
+        assert!(!matches_pattern("*.paras.near", "paras.near"));
+        assert!(matches_pattern("*", "anything.near"));
+    }
+
+    #[test]
+    fn matches_pattern_with_several_wildcards() {
+        assert!(matches_pattern("nft-*.*.near", "nft-1.store.near"));
+        assert!(matches_pattern("a*b*c", "abc"));
+        assert!(matches_pattern("a*b*c", "axxbyyc"));
+        assert!(matches_pattern("*market*", "my-market.near"));
+        assert!(matches_pattern("**.near", "x.near"));
+        assert!(!matches_pattern("a*b*c", "acb"));
+        assert!(!matches_pattern("nft-*.*.near", "nft-1.near"));
+        // The prefix and the suffix may not overlap
+        assert!(!matches_pattern("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn validate_account_id_accepts_accounts_and_patterns() {
+        assert!(validate_account_id("nft.near").is_ok());
+        assert!(validate_account_id("*.paras.near").is_ok());
+        assert!(validate_account_id("nft-*.*.near").is_ok());
+    }
+
+    #[test]
+    fn validate_account_id_rejects_invalid_accounts() {
+        assert!(validate_account_id("").is_err());
+        assert!(validate_account_id("Nft.near").is_err());
+        assert!(validate_account_id("nft..near").is_err());
+        assert!(validate_account_id("nft near").is_err());
+    }
+}
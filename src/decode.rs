@@ -0,0 +1,106 @@
+use near_indexer::near_primitives::views::{ ExecutionOutcomeWithIdView, ExecutionStatusView };
+use serde_json::Value;
+
+use crate::events::{ self, IndexedEvent };
+
+fn pretty(value: &impl serde::Serialize) -> String {
+    serde_json::to_string_pretty(value).expect("Decoded values are always serializable").replace('\n', "\n      ")
+}
+
+/// Prints every step `process_outcome` takes with one log. Returns whether it decoded.
+fn decode_log(log: &str, log_index: usize, contract_id: &str, receipt_id: &str) -> bool {
+    println!("📜 Log {}: {}", log_index, log);
+
+    let parsed_log = match events::parse_log(log) {
+        Ok(parsed_log) => parsed_log,
+        Err(err) => {
+            println!("  ❌ Not JSON, the log is skipped as faulty: {}", err);
+            return false;
+        }
+    };
+
+    let prefix = match log.starts_with("EVENT_JSON:") {
+        true => "EVENT_JSON: prefix",
+        false => "no EVENT_JSON: prefix, parsed as plain JSON",
+    };
+    let envelope_field = |key: &str| parsed_log[key].as_str().map_or("-".to_string(), str::to_string);
+    println!("  Envelope: {}, standard {}, version {}, event {}", prefix, envelope_field("standard"), envelope_field("version"), envelope_field("event"));
+
+    let event_type = parsed_log["event"].as_str().unwrap_or("None");
+    if !events::EVENT_TYPES.contains(&event_type) {
+        println!("  Handler: none for event '{}', the log is ignored", event_type);
+        return true;
+    }
+    println!("  Handler: {}", event_type);
+
+    let payloads = match events::decode_payloads(contract_id, &parsed_log) {
+        Ok(payloads) => payloads,
+        Err(err) => {
+            println!("  ❌ Invalid {} log, it is skipped as malformed: {}", event_type, err);
+            return false;
+        }
+    };
+    if payloads.is_empty() {
+        println!("  No entries in `data`, nothing is delivered");
+    }
+
+    for (entry_index, payload) in payloads.into_iter().enumerate() {
+        // There is no block to take the position from
+        let event = IndexedEvent {
+            event_id: format!("{}:{}:{}", receipt_id, log_index, entry_index),
+            contract_id: contract_id.to_string(),
+            receipt_id: receipt_id.to_string(),
+            block_height: 0,
+            block_timestamp: 0,
+            shard_id: 0,
+            outcome_index: 0,
+            log_index: log_index as u64,
+            payload,
+        };
+
+        println!("  ✅ Entry {} decodes to {}, stored in `{}`", entry_index, event.payload.event_type(), event.payload.collection());
+        match event.payload.api_path() {
+            Some(api_path) => println!("    Fields, as POSTed to {{public_api}}/{}:\n      {}", api_path, pretty(&event.payload.data())),
+            None => println!("    Not POSTed to {{public_api}}, which has no route for it"),
+        }
+        println!("    Event, as written by the mongodb, file and stdout sinks:\n      {}", pretty(&event));
+    }
+
+    true
+}
+
+/// `decode`: shows how a raw log, or every log of a receipt outcome JSON, would be
+/// interpreted, without connecting to or delivering anything. A receipt outcome gives the
+/// contract and receipt ids, a bare log uses `contract_id`. Returns whether every log decoded.
+pub fn decode(input: &str, contract_id: &str) -> bool {
+    let input = input.trim();
+    let outcome_json = serde_json::from_str::<Value>(input).ok()
+        .map(|value| value.get("execution_outcome").cloned().unwrap_or(value))
+        .filter(|value| value.get("outcome").is_some());
+
+    let outcome_json = match outcome_json {
+        Some(outcome_json) => outcome_json,
+        None => return decode_log(input, 0, contract_id, "decode"),
+    };
+    let execution_outcome: ExecutionOutcomeWithIdView = match serde_json::from_value(outcome_json) {
+        Ok(execution_outcome) => execution_outcome,
+        Err(err) => {
+            println!("❌ Not a valid receipt outcome: {}", err);
+            return false;
+        }
+    };
+
+    let outcome = &execution_outcome.outcome;
+    let receipt_id = execution_outcome.id.to_string();
+    println!("🧾 Receipt {} executed by {} with {} logs", receipt_id, outcome.executor_id, outcome.logs.len());
+    if !matches!(outcome.status, ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)) {
+        println!("  ⚠️  Status {:?}: unsuccessful receipts are skipped, the logs below are never processed", outcome.status);
+    }
+
+    let mut decoded = true;
+    for (log_index, log) in outcome.logs.iter().enumerate() {
+        decoded &= decode_log(log, log_index, outcome.executor_id.as_str(), &receipt_id);
+    }
+
+    decoded
+}
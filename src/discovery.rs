@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use mongodb::{ Database, Collection, options::{ FindOptions, UpdateOptions } };
+use bson::{ doc, document::Document };
+use near_indexer::near_primitives::views::{ ExecutionOutcomeWithIdView, ExecutionStatusView };
+use tokio_stream::StreamExt;
+
+use crate::events;
+
+pub const DISCOVERED_COLLECTION: &str = "discovered_contracts";
+
+/// Event types come from arbitrary logs on chain. Only plain names are used as keys of
+/// `events`, a `.`, a leading `$` or an empty name would turn the update invalid.
+fn event_key(event_type: &str) -> &str {
+    let plain = event_type.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    match plain && !event_type.is_empty() && event_type.len() <= 64 {
+        true => event_type,
+        false => "invalid_name",
+    }
+}
+
+/// Records contracts emitting NEP-171 events anywhere on chain without delivering
+/// their events, so operators can find collections worth watching.
+#[derive(Clone)]
+pub struct Discovery {
+    database: Database,
+}
+
+impl Discovery {
+    pub fn new(database: Database) -> Self {
+        Self { database }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.database.collection(DISCOVERED_COLLECTION)
+    }
+
+    /// NEP-171 event types found in the logs of a successful outcome.
+    pub fn scan_outcome(execution_outcome: &ExecutionOutcomeWithIdView) -> Vec<String> {
+        match &execution_outcome.outcome.status {
+            ExecutionStatusView::SuccessValue(_) => (),
+            ExecutionStatusView::SuccessReceiptId(_) => (),
+            _ => return vec![]
+        }
+
+        execution_outcome.outcome.logs.iter()
+            .filter(|log| log.starts_with("EVENT_JSON:"))
+            .filter_map(|log| events::parse_log(log).ok())
+            .filter(|parsed_log| parsed_log["standard"].as_str() == Some("nep171"))
+            .map(|parsed_log| parsed_log["event"].as_str().unwrap_or("None").to_string())
+            .collect()
+    }
+
+    /// Adds one block's findings, keyed by contract, to the running counts.
+    pub async fn record_block(&self, block_height: u64, found: &HashMap<String, Vec<String>>) -> Result<(), mongodb::error::Error> {
+        let collection = self.collection();
+        let options = UpdateOptions::builder().upsert(true).build();
+        let height = block_height as i64;
+
+        for (account_id, event_types) in found {
+            let mut increments = doc! { "event_count": event_types.len() as i64 };
+            for event_type in event_types {
+                let key = format!("events.{}", event_key(event_type));
+                let count = increments.get_i64(&key).unwrap_or(0);
+                increments.insert(key, count + 1);
+            }
+
+            collection.update_one(
+                doc! { "_id": account_id.clone() },
+                doc! {
+                    "$min": { "first_seen_height": height },
+                    "$max": { "last_seen_height": height },
+                    "$inc": increments,
+                    "$setOnInsert": { "promoted": false },
+                },
+                options.clone(),
+            ).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Discovered contracts, most active first.
+    pub async fn list(&self, include_promoted: bool) -> Result<Vec<Document>, mongodb::error::Error> {
+        let filter = match include_promoted {
+            true => doc! {},
+            false => doc! { "promoted": false },
+        };
+        let options = FindOptions::builder().sort(doc! { "event_count": -1 }).build();
+        let mut cursor = self.collection().find(filter, options).await?;
+        let mut discovered = vec![];
+
+        while let Some(document) = cursor.next().await {
+            let mut document = document?;
+            if let Ok(account_id) = document.get_str("_id").map(str::to_string) {
+                document.remove("_id");
+                document.insert("account_id", account_id);
+            }
+            discovered.push(document);
+        }
+
+        Ok(discovered)
+    }
+
+    pub async fn get(&self, account_id: &str) -> Result<Option<Document>, mongodb::error::Error> {
+        self.collection().find_one(doc! { "_id": account_id }, None).await
+    }
+
+    pub async fn mark_promoted(&self, account_id: &str) -> Result<(), mongodb::error::Error> {
+        self.collection().update_one(doc! { "_id": account_id }, doc! { "$set": { "promoted": true } }, None).await?;
+        Ok(())
+    }
+}
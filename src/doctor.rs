@@ -0,0 +1,303 @@
+use std::fs::{ self, File, OpenOptions };
+use std::io::BufReader;
+use std::path::Path;
+use std::time::Duration;
+use bson::doc;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::Capacitor;
+use crate::auth::ApiTokens;
+use crate::configs::{ Config, NodeConfig, SyncMode };
+use crate::contracts::WatchedContract;
+use crate::database;
+use crate::events::{ EventPayload, IndexedEvent, TransferredTokens };
+use crate::migrations;
+use crate::sinks::{ self, EventSink, HttpSink, SinkConfig, SinkKind };
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Account named in the test event, so consumers can recognise and drop it.
+const PROBE_ACCOUNT: &str = "capacitor-doctor.test";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Ok,
+    Warning,
+    Failed,
+}
+
+struct Check {
+    status: Status,
+    name: String,
+    details: String,
+}
+
+/// Results of every check, printed once all of them ran.
+#[derive(Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn add(&mut self, status: Status, name: &str, details: impl ToString) {
+        self.checks.push(Check { status, name: name.to_string(), details: details.to_string() });
+    }
+
+    fn ok(&mut self, name: &str, details: impl ToString) {
+        self.add(Status::Ok, name, details);
+    }
+
+    fn warn(&mut self, name: &str, details: impl ToString) {
+        self.add(Status::Warning, name, details);
+    }
+
+    fn fail(&mut self, name: &str, details: impl ToString) {
+        self.add(Status::Failed, name, details);
+    }
+
+    fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.status != Status::Failed)
+    }
+
+    fn print(&self) {
+        for check in &self.checks {
+            let icon = match check.status {
+                Status::Ok => "✅",
+                Status::Warning => "⚠️ ",
+                Status::Failed => "❌",
+            };
+            println!("{} {}: {}", icon, check.name, check.details);
+        }
+    }
+}
+
+/// The start of `genesis.json`; the records that follow are skipped.
+#[derive(Deserialize)]
+struct GenesisHeader {
+    chain_id: String,
+    genesis_height: u64,
+}
+
+/// Checks the files `init` writes and that the genesis belongs to `node.chain_id`.
+fn check_home_dir(node: &NodeConfig, report: &mut Report) {
+    let home_dir = node.home_dir();
+    if !home_dir.is_dir() {
+        return report.fail("node home dir", format!("{} does not exist, run `init` first", home_dir.display()));
+    }
+
+    let near_config: Value = match fs::read_to_string(home_dir.join("config.json")).map_err(|err| err.to_string()).and_then(|contents| serde_json::from_str(&contents).map_err(|err| err.to_string())) {
+        Ok(near_config) => near_config,
+        Err(err) => return report.fail("config.json", format!("{} has no valid config.json: {}", home_dir.display(), err)),
+    };
+    match near_config["archive"].as_bool().unwrap_or(false) {
+        true => report.ok("config.json", "archival node"),
+        false if node.sync_mode == SyncMode::BlockHeight => report.warn("config.json", "not an archival node, old start heights and backfill jobs may reach blocks it does not keep"),
+        false => report.ok("config.json", "non-archival node, backfill jobs only reach the blocks it keeps"),
+    }
+
+    let genesis_path = home_dir.join(near_config["genesis_file"].as_str().unwrap_or("genesis.json"));
+    let genesis: Result<GenesisHeader, String> = File::open(&genesis_path)
+        .map_err(|err| err.to_string())
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).map_err(|err| err.to_string()));
+    match (genesis, &node.chain_id) {
+        (Err(err), _) => report.fail("genesis", format!("{} is not a valid genesis: {}", genesis_path.display(), err)),
+        (Ok(genesis), Some(chain_id)) if &genesis.chain_id != chain_id => {
+            report.fail("genesis", format!("belongs to chain {} but node.chain_id is {}", genesis.chain_id, chain_id))
+        }
+        (Ok(genesis), _) => report.ok("genesis", format!("chain {} starting at height {}", genesis.chain_id, genesis.genesis_height)),
+    }
+
+    if !home_dir.join("node_key.json").is_file() {
+        report.fail("node key", format!("{} has no node_key.json", home_dir.display()));
+    }
+}
+
+fn check_files(config: &Config, sink_configs: &[SinkConfig], report: &mut Report) {
+    let listeners = std::iter::once(("admin listener", Some(&config.http.admin))).chain(std::iter::once(("public listener", config.http.public.as_ref())));
+
+    for (name, listener) in listeners {
+        let tls = match listener.and_then(|listener| listener.tls.as_ref()) {
+            Some(tls) => tls,
+            None => continue,
+        };
+        for path in [&tls.cert_path, &tls.key_path].iter() {
+            if !Path::new(path).is_file() {
+                report.fail(name, format!("TLS file {} does not exist", path));
+            }
+        }
+    }
+
+    for sink in sink_configs {
+        if let SinkKind::File { path } = &sink.kind {
+            let directory = Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if !directory.is_dir() {
+                report.fail(&format!("sink {}", sink.name), format!("directory of {} does not exist", path));
+            }
+        }
+    }
+}
+
+/// Every check that needs nothing but the local files.
+fn offline_checks(config: &Config, report: &mut Report) -> Vec<SinkConfig> {
+    report.ok("config", "parsed and valid");
+    check_home_dir(&config.node, report);
+
+    match ApiTokens::load(&config.auth) {
+        Ok(_) => report.ok("admin tokens", "loaded"),
+        Err(err) => report.fail("admin tokens", err),
+    }
+
+    let sink_configs = match sinks::load_sink_configs(config) {
+        Ok(sink_configs) => sink_configs,
+        Err(err) => {
+            report.fail("sinks", err);
+            vec![]
+        }
+    };
+    check_files(config, &sink_configs, report);
+
+    sink_configs
+}
+
+fn print_summary(config: &Config, contracts: &[WatchedContract], sink_configs: &[SinkConfig]) {
+    let sync_mode = match config.node.sync_mode {
+        SyncMode::BlockHeight => format!("from block {}", config.node.start_height.unwrap_or_default()),
+        sync_mode => format!("{:?}", sync_mode),
+    };
+    println!("⛏ Node {} ({}), syncing {}", config.node.home_dir().display(), config.node.chain_id.as_deref().unwrap_or("any chain"), sync_mode);
+    println!("🗄 MongoDB database {}", config.mongodb.database);
+
+    println!("📝 Would watch {} contracts:", contracts.len());
+    for contract in contracts {
+        println!("  - {} ({})", contract.account_id, contract.describe());
+    }
+
+    println!("📮 Would deliver to {} sinks:", sink_configs.len());
+    for sink in sink_configs {
+        let destination = match &sink.kind {
+            SinkKind::Http { url } => format!("http {}", url),
+            SinkKind::MongoDb => "mongodb".to_string(),
+            SinkKind::File { path } => format!("file {}", path),
+            SinkKind::Stdout => "stdout".to_string(),
+        };
+        let event_types = sink.event_types.as_ref().map_or("all events".to_string(), |event_types| event_types.join(","));
+        println!("  - {}: {} ({})", sink.name, destination, event_types);
+    }
+
+    match &config.http.public {
+        Some(public) => println!("🌐 Admin API on {}, public API on {}", config.http.admin.socket_address(), public.socket_address()),
+        None => println!("🔐 Admin API on {}", config.http.admin.socket_address()),
+    }
+}
+
+fn configured_contracts(config: &Config) -> Vec<WatchedContract> {
+    config.contracts.iter().map(|account_id| WatchedContract::new(account_id.clone(), "config")).collect()
+}
+
+/// `check-config`: validates the configuration and the node home dir without connecting
+/// to anything. Returns whether every check passed.
+pub fn check_config(config: &Config) -> bool {
+    let mut report = Report::default();
+    let sink_configs = offline_checks(config, &mut report);
+
+    print_summary(config, &configured_contracts(config), &sink_configs);
+    report.print();
+    report.passed()
+}
+
+/// An `nft_transfer` of a made-up token from and to `PROBE_ACCOUNT`, which changes nothing
+/// for a consumer that does process it.
+fn probe_event() -> IndexedEvent {
+    IndexedEvent {
+        event_id: "capacitor-doctor:0:0".to_string(),
+        contract_id: PROBE_ACCOUNT.to_string(),
+        receipt_id: "capacitor-doctor".to_string(),
+        block_height: 0,
+        block_timestamp: chrono::Utc::now().timestamp_nanos() as u64,
+        shard_id: 0,
+        outcome_index: 0,
+        log_index: 0,
+        payload: EventPayload::NftTransfer(TransferredTokens {
+            token_ids: vec!["capacitor-doctor-probe".to_string()],
+            contract_id: PROBE_ACCOUNT.to_string(),
+            old_owner_id: PROBE_ACCOUNT.to_string(),
+            new_owner_id: PROBE_ACCOUNT.to_string(),
+        }),
+    }
+}
+
+async fn probe_sink(sink: &SinkConfig, config: &Config, mongodb_reachable: bool, report: &mut Report) {
+    let name = format!("sink {}", sink.name);
+
+    match &sink.kind {
+        SinkKind::Http { url } => {
+            let http_sink = HttpSink::new(sink.name.clone(), url.clone(), config.auth.api_token.clone().unwrap_or_default());
+            match tokio::time::timeout(PROBE_TIMEOUT, http_sink.deliver(&probe_event())).await {
+                Ok(Ok(())) => report.ok(&name, format!("{} accepted a signed test event", url)),
+                Ok(Err(err)) => report.fail(&name, format!("{} rejected a signed test event: {}", url, err)),
+                Err(_) => report.fail(&name, format!("{} did not answer within {}s", url, PROBE_TIMEOUT.as_secs())),
+            }
+        }
+        SinkKind::MongoDb => match mongodb_reachable {
+            true => report.ok(&name, "writes to the MongoDB checked above"),
+            false => report.fail(&name, "MongoDB is unreachable"),
+        },
+        SinkKind::File { path } => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(_) => report.ok(&name, format!("{} is writable", path)),
+            Err(err) => report.fail(&name, format!("{} is not writable: {}", path, err)),
+        },
+        SinkKind::Stdout => report.ok(&name, "prints to stdout"),
+    }
+}
+
+/// `doctor`: runs the checks of `check-config`, then checks MongoDB and its schema version
+/// and probes every sink. Returns whether every check passed.
+pub async fn doctor(config: &Config) -> bool {
+    let mut report = Report::default();
+    let sink_configs = offline_checks(config, &mut report);
+    let mut contracts = configured_contracts(config);
+    let mut mongodb_reachable = false;
+
+    match database::try_connect(&config.mongodb).await {
+        Err(err) => report.fail("mongodb", err),
+        Ok(client) => {
+            let database = client.database(&config.mongodb.database);
+
+            match database.run_command(doc! { "ping": 1 }, None).await {
+                Err(err) => report.fail("mongodb", format!("unreachable: {}", err)),
+                Ok(_) => {
+                    mongodb_reachable = true;
+                    report.ok("mongodb", "reachable");
+
+                    if config.indexing.projections {
+                        match database.run_command(doc! { "isMaster": 1 }, None).await {
+                            Ok(status) if status.get_str("setName").is_ok() => report.ok("projections", "MongoDB runs as a replica set"),
+                            Ok(_) => report.fail("projections", "MongoDB is standalone, the projections' transactions need a replica set"),
+                            Err(err) => report.fail("projections", format!("could not tell whether MongoDB runs as a replica set: {}", err)),
+                        }
+                    }
+
+                    let latest = migrations::latest_version();
+                    match migrations::current_version(&database).await {
+                        Ok(current) if current == latest => report.ok("schema", format!("version {}", current)),
+                        Ok(current) if current < latest => report.warn("schema", format!("version {}, migrations up to {} are applied on the next run", current, latest)),
+                        Ok(current) => report.fail("schema", format!("version {} is newer than the {} supported by this build", current, latest)),
+                        Err(err) => report.fail("schema", err),
+                    }
+
+                    let mut capacitor_ins = Capacitor::new(client, &config.mongodb.database, config.contracts.clone());
+                    capacitor_ins.load().await;
+                    contracts = capacitor_ins.contracts();
+                }
+            }
+        }
+    }
+
+    for sink in &sink_configs {
+        probe_sink(sink, config, mongodb_reachable, &mut report).await;
+    }
+
+    print_summary(config, &contracts, &sink_configs);
+    report.print();
+    report.passed()
+}
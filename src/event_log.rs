@@ -0,0 +1,53 @@
+use mongodb::{ Database, Collection, Cursor, options::{ FindOptions, UpdateOptions } };
+use bson::{ doc, document::Document };
+
+use crate::events::IndexedEvent;
+
+pub const EVENTS_COLLECTION: &str = "events";
+
+/// Append-only log of every decoded event, in chain order. Projections and
+/// sinks only ever see events that made it into the log first.
+#[derive(Clone)]
+pub struct EventLog {
+    database: Database,
+}
+
+impl EventLog {
+    pub fn new(database: Database) -> Self {
+        Self { database }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.database.collection(EVENTS_COLLECTION)
+    }
+
+    /// Appends the events of a block. Events already in the log are left untouched,
+    /// so reprocessing a block never rewrites history.
+    pub async fn append(&self, events: &[IndexedEvent]) -> Result<(), mongodb::error::Error> {
+        let collection = self.collection();
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        for event in events {
+            let mut document = event.to_document();
+            document.remove("_id");
+
+            collection.update_one(event.id_filter(), doc! { "$setOnInsert": document }, options.clone()).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn get(&self, event_id: &str) -> Result<Option<IndexedEvent>, mongodb::error::Error> {
+        let document = self.collection().find_one(doc! { "_id": event_id }, None).await?;
+        Ok(document.and_then(IndexedEvent::from_document))
+    }
+
+    /// Every event from `from_height` on, ordered by block height, shard, outcome and log index.
+    pub async fn read_from(&self, from_height: u64) -> Result<Cursor<Document>, mongodb::error::Error> {
+        let options = FindOptions::builder()
+            .sort(doc! { "block_height": 1, "shard_id": 1, "outcome_index": 1, "log_index": 1, "_id": 1 })
+            .build();
+
+        self.collection().find(doc! { "block_height": { "$gte": from_height as i64 } }, options).await
+    }
+}
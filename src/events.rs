@@ -0,0 +1,464 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{ Value };
+use bson::{ Bson, doc, document::Document };
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MintedTokens {
+    pub token_ids: Vec<String>,
+    pub contract_id: String,
+    pub owner_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransferredTokens {
+    pub token_ids: Vec<String>,
+    pub contract_id: String,
+    pub old_owner_id: String,
+    pub new_owner_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BurnedTokens {
+    pub token_ids: Vec<String>,
+    pub contract_id: String,
+    pub owner_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MarketListing {
+    pub token_id: String,
+    pub nft_contract_id: String,
+    pub owner_id: String,
+    pub approval_id: u64,
+    pub ft_token_id: String,
+    pub price: String,
+    pub started_at: String,
+    pub ended_at: String,
+    pub is_auction: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MarketListingUpdate {
+    pub token_id: String,
+    pub nft_contract_id: String,
+    pub owner_id: String,
+    pub ft_token_id: String,
+    pub price: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MarketUnlisting {
+    pub token_id: String,
+    pub nft_contract_id: String,
+    pub owner_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MarketBid {
+    pub token_id: String,
+    pub nft_contract_id: String,
+    pub bidder_id: String,
+    pub ft_token_id: String,
+    pub price: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MarketOffer {
+    pub token_id: String,
+    pub nft_contract_id: String,
+    pub buyer_id: String,
+    pub ft_token_id: String,
+    pub price: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MarketUnoffer {
+    pub token_id: String,
+    pub nft_contract_id: String,
+    pub buyer_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MarketPurchase {
+    pub token_id: String,
+    pub nft_contract_id: String,
+    pub owner_id: String,
+    pub ft_token_id: String,
+    pub price: String,
+    pub buyer_id: String,
+    pub is_offer: bool,
+}
+
+/// A single event emitted by a watched contract, tagged by its `event` name.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum EventPayload {
+    NftMint(MintedTokens),
+    NftTransfer(TransferredTokens),
+    NftBurn(BurnedTokens),
+    AddMarketData(MarketListing),
+    UpdateMarketData(MarketListingUpdate),
+    DeleteMarketData(MarketUnlisting),
+    AddBid(MarketBid),
+    AddOffer(MarketOffer),
+    DeleteOffer(MarketUnoffer),
+    ResolvePurchase(MarketPurchase),
+}
+
+impl EventPayload {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            EventPayload::NftMint(_) => "nft_mint",
+            EventPayload::NftTransfer(_) => "nft_transfer",
+            EventPayload::NftBurn(_) => "nft_burn",
+            EventPayload::AddMarketData(_) => "add_market_data",
+            EventPayload::UpdateMarketData(_) => "update_market_data",
+            EventPayload::DeleteMarketData(_) => "delete_market_data",
+            EventPayload::AddBid(_) => "add_bid",
+            EventPayload::AddOffer(_) => "add_offer",
+            EventPayload::DeleteOffer(_) => "delete_offer",
+            EventPayload::ResolvePurchase(_) => "resolve_purchase",
+        }
+    }
+
+    /// Path on the `PUBLIC_API` root the event is posted to. `PUBLIC_API` has no route
+    /// for burns, so they are not posted to it.
+    pub fn api_path(&self) -> Option<&'static str> {
+        match self {
+            EventPayload::NftMint(_) => Some("insert_tokens"),
+            EventPayload::NftTransfer(_) => Some("transfer_tokens"),
+            EventPayload::NftBurn(_) => None,
+            EventPayload::AddMarketData(_) => Some("list_token"),
+            EventPayload::UpdateMarketData(_) => Some("update_token"),
+            EventPayload::DeleteMarketData(_) => Some("unlist_token"),
+            EventPayload::AddBid(_) => Some("bid_token"),
+            EventPayload::AddOffer(_) => Some("offer_token"),
+            EventPayload::DeleteOffer(_) => Some("unoffer_token"),
+            EventPayload::ResolvePurchase(_) => Some("resolve_token"),
+        }
+    }
+
+    /// MongoDB collection the event is written to.
+    pub fn collection(&self) -> &'static str {
+        match self {
+            EventPayload::NftMint(_) => "nft_mints",
+            EventPayload::NftTransfer(_) => "nft_transfers",
+            EventPayload::NftBurn(_) => "nft_burns",
+            EventPayload::AddMarketData(_) => "market_listings",
+            EventPayload::UpdateMarketData(_) => "market_listings",
+            EventPayload::DeleteMarketData(_) => "market_listings",
+            EventPayload::AddBid(_) => "market_bids",
+            EventPayload::AddOffer(_) => "market_offers",
+            EventPayload::DeleteOffer(_) => "market_offers",
+            EventPayload::ResolvePurchase(_) => "market_sales",
+        }
+    }
+
+    /// The bare event data, as it is posted to the `PUBLIC_API`.
+    pub fn data(&self) -> Value {
+        let data = match self {
+            EventPayload::NftMint(data) => serde_json::to_value(data),
+            EventPayload::NftTransfer(data) => serde_json::to_value(data),
+            EventPayload::NftBurn(data) => serde_json::to_value(data),
+            EventPayload::AddMarketData(data) => serde_json::to_value(data),
+            EventPayload::UpdateMarketData(data) => serde_json::to_value(data),
+            EventPayload::DeleteMarketData(data) => serde_json::to_value(data),
+            EventPayload::AddBid(data) => serde_json::to_value(data),
+            EventPayload::AddOffer(data) => serde_json::to_value(data),
+            EventPayload::DeleteOffer(data) => serde_json::to_value(data),
+            EventPayload::ResolvePurchase(data) => serde_json::to_value(data),
+        };
+
+        data.expect("Event data is always serializable")
+    }
+}
+
+/// Block height, shard, outcome index, log index and entry index of an event.
+pub type EventPosition = (u64, u64, u64, u64, u64);
+
+/// A decoded event together with the position it was found at on chain.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IndexedEvent {
+    /// `<receipt_id>:<log_index>:<entry_index>`, unique across the chain
+    pub event_id: String,
+    pub contract_id: String,
+    pub receipt_id: String,
+    pub block_height: u64,
+    pub block_timestamp: u64,
+    pub shard_id: u64,
+    /// Position of the receipt outcome within its shard
+    pub outcome_index: u64,
+    pub log_index: u64,
+    pub payload: EventPayload,
+}
+
+impl IndexedEvent {
+    pub fn to_document(&self) -> Document {
+        let value = serde_json::to_value(self).expect("Event is always serializable");
+        let mut document = match Bson::from(value) {
+            Bson::Document(document) => document,
+            _ => unreachable!("Event always serializes to an object"),
+        };
+
+        document.insert("_id", self.event_id.clone());
+        document
+    }
+
+    pub fn from_document(mut document: Document) -> Option<Self> {
+        document.remove("_id");
+        serde_json::from_value(Bson::Document(document).into_relaxed_extjson()).ok()
+    }
+
+    /// Orders events the way they were executed on chain.
+    pub fn sort_key(&self) -> EventPosition {
+        (self.block_height, self.shard_id, self.outcome_index, self.log_index, self.entry_index())
+    }
+
+    /// Index of the event among the entries of its log, the last part of the event id.
+    pub fn entry_index(&self) -> u64 {
+        self.event_id.rsplit(':').next().and_then(|index| index.parse().ok()).unwrap_or(0)
+    }
+
+    pub fn id_filter(&self) -> Document {
+        doc! { "_id": self.event_id.clone() }
+    }
+}
+
+/// Strips the optional `EVENT_JSON:` prefix and parses the log as JSON.
+pub fn parse_log(log: &str) -> Result<Value, String> {
+    let log_str = match log.strip_prefix("EVENT_JSON:") {
+        Some(stripped) => stripped,
+        None => log,
+    };
+
+    serde_json::from_str(log_str).map_err(|err| err.to_string())
+}
+
+fn str_field(value: &Value, key: &str) -> Result<String, String> {
+    value[key].as_str().map(str::to_string).ok_or(format!("`{}` is missing or not a string", key))
+}
+
+fn str_field_or(value: &Value, key: &str, default: &str) -> String {
+    value[key].as_str().unwrap_or(default).to_string()
+}
+
+fn token_ids_field(value: &Value) -> Result<Vec<String>, String> {
+    let token_ids = value["token_ids"].as_array().ok_or("`token_ids` is missing or not an array")?;
+
+    token_ids.iter()
+        .map(|token_id| token_id.as_str().map(str::to_string).ok_or("`token_ids` contains a non string value".to_string()))
+        .collect()
+}
+
+fn data_entries(parsed_log: &Value) -> Result<&Vec<Value>, String> {
+    parsed_log["data"].as_array().ok_or("`data` is missing or not an array".to_string())
+}
+
+/// Event types `decode_payloads` has a handler for.
+pub const EVENT_TYPES: [&str; 10] = [
+    "nft_mint", "nft_transfer", "nft_burn",
+    "add_market_data", "update_market_data", "delete_market_data",
+    "add_bid", "add_offer", "delete_offer", "resolve_purchase",
+];
+
+/// Turns a parsed log into the events it describes. Logs of unknown event types
+/// decode to no events at all.
+pub fn decode_payloads(contract_id: &str, parsed_log: &Value) -> Result<Vec<EventPayload>, String> {
+    let log_type = parsed_log["event"].as_str().unwrap_or("None");
+    let params = &parsed_log["params"];
+
+    let payloads = match log_type {
+        "nft_mint" => data_entries(parsed_log)?.iter().map(|entry| {
+            Ok(EventPayload::NftMint(MintedTokens {
+                token_ids: token_ids_field(entry)?,
+                contract_id: contract_id.to_string(),
+                owner_id: str_field(entry, "owner_id")?,
+            }))
+        }).collect::<Result<Vec<_>, String>>()?,
+        "nft_transfer" => data_entries(parsed_log)?.iter().map(|entry| {
+            Ok(EventPayload::NftTransfer(TransferredTokens {
+                token_ids: token_ids_field(entry)?,
+                contract_id: contract_id.to_string(),
+                old_owner_id: str_field(entry, "old_owner_id")?,
+                new_owner_id: str_field(entry, "new_owner_id")?,
+            }))
+        }).collect::<Result<Vec<_>, String>>()?,
+        "nft_burn" => data_entries(parsed_log)?.iter().map(|entry| {
+            Ok(EventPayload::NftBurn(BurnedTokens {
+                token_ids: token_ids_field(entry)?,
+                contract_id: contract_id.to_string(),
+                owner_id: str_field(entry, "owner_id")?,
+            }))
+        }).collect::<Result<Vec<_>, String>>()?,
+        "add_market_data" => vec![EventPayload::AddMarketData(MarketListing {
+            token_id: str_field_or(params, "token_id", "None"),
+            nft_contract_id: str_field(params, "nft_contract_id")?,
+            owner_id: str_field(params, "owner_id")?,
+            approval_id: params["approval_id"].as_u64().ok_or("`approval_id` is missing or not a number")?,
+            ft_token_id: str_field(params, "ft_token_id")?,
+            price: str_field(params, "price")?,
+            started_at: str_field_or(params, "started_at", "0"),
+            ended_at: str_field_or(params, "ended_at", "0"),
+            is_auction: params["is_auction"].as_bool().unwrap_or(false),
+        })],
+        "update_market_data" => vec![EventPayload::UpdateMarketData(MarketListingUpdate {
+            token_id: str_field_or(params, "token_id", "None"),
+            nft_contract_id: str_field(params, "nft_contract_id")?,
+            owner_id: str_field(params, "owner_id")?,
+            ft_token_id: str_field(params, "ft_token_id")?,
+            price: str_field(params, "price")?,
+        })],
+        "delete_market_data" => vec![EventPayload::DeleteMarketData(MarketUnlisting {
+            token_id: str_field_or(params, "token_id", "None"),
+            nft_contract_id: str_field(params, "nft_contract_id")?,
+            owner_id: str_field(params, "owner_id")?,
+        })],
+        "add_bid" => vec![EventPayload::AddBid(MarketBid {
+            token_id: str_field_or(params, "token_id", "None"),
+            nft_contract_id: str_field(params, "nft_contract_id")?,
+            bidder_id: str_field(params, "bidder_id")?,
+            ft_token_id: str_field(params, "ft_token_id")?,
+            price: str_field(params, "amount")?,
+        })],
+        "add_offer" => vec![EventPayload::AddOffer(MarketOffer {
+            token_id: str_field_or(params, "token_id", "None"),
+            nft_contract_id: str_field(params, "nft_contract_id")?,
+            buyer_id: str_field(params, "buyer_id")?,
+            ft_token_id: str_field(params, "ft_token_id")?,
+            price: str_field(params, "price")?,
+        })],
+        "delete_offer" => vec![EventPayload::DeleteOffer(MarketUnoffer {
+            token_id: str_field_or(params, "token_id", "None"),
+            nft_contract_id: str_field(params, "nft_contract_id")?,
+            buyer_id: str_field(params, "buyer_id")?,
+        })],
+        "resolve_purchase" => vec![EventPayload::ResolvePurchase(MarketPurchase {
+            token_id: str_field_or(params, "token_id", "None"),
+            nft_contract_id: str_field(params, "nft_contract_id")?,
+            owner_id: str_field(params, "owner_id")?,
+            ft_token_id: str_field(params, "ft_token_id")?,
+            price: str_field(params, "price")?,
+            buyer_id: str_field(params, "buyer_id")?,
+            is_offer: params["is_offer"].as_bool().unwrap_or(false),
+        })],
+        _ => vec![],
+    };
+
+    Ok(payloads)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parse_log_accepts_prefixed_and_plain_json() {
+        let prefixed = parse_log(r#"EVENT_JSON:{"standard":"nep171","event":"nft_mint"}"#).unwrap();
+        let plain = parse_log(r#"{"event":"add_bid"}"#).unwrap();
+
+        assert_eq!(prefixed["event"], "nft_mint");
+        assert_eq!(plain["event"], "add_bid");
+    }
+
+    #[test]
+    fn parse_log_rejects_malformed_logs() {
+        assert!(parse_log("Transfer 1 NEAR to alice.near").is_err());
+        assert!(parse_log("EVENT_JSON:").is_err());
+        assert!(parse_log(r#"EVENT_JSON:{"event":"nft_mint""#).is_err());
+    }
+
+    #[test]
+    fn decode_payloads_decodes_every_data_entry() {
+        let log = json!({
+            "standard": "nep171",
+            "event": "nft_transfer",
+            "data": [
+                { "old_owner_id": "alice.near", "new_owner_id": "bob.near", "token_ids": ["1", "2"] },
+                { "old_owner_id": "bob.near", "new_owner_id": "carol.near", "token_ids": ["3"] },
+            ],
+        });
+
+        let payloads = decode_payloads("nft.near", &log).unwrap();
+
+        assert_eq!(payloads.len(), 2);
+        match &payloads[0] {
+            EventPayload::NftTransfer(transfer) => {
+                assert_eq!(transfer.contract_id, "nft.near");
+                assert_eq!(transfer.new_owner_id, "bob.near");
+                assert_eq!(transfer.token_ids, vec!["1", "2"]);
+            }
+            _ => panic!("Expected an nft_transfer"),
+        }
+    }
+
+    #[test]
+    fn decode_payloads_ignores_unknown_events() {
+        assert!(decode_payloads("nft.near", &json!({ "event": "ft_transfer", "data": [] })).unwrap().is_empty());
+        assert!(decode_payloads("nft.near", &json!({ "standard": "nep171" })).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decode_payloads_rejects_malformed_entries() {
+        let cases = vec![
+            json!({ "event": "nft_mint" }),
+            json!({ "event": "nft_mint", "data": {} }),
+            json!({ "event": "nft_mint", "data": [{ "owner_id": "alice.near" }] }),
+            json!({ "event": "nft_mint", "data": [{ "owner_id": "alice.near", "token_ids": [1] }] }),
+            json!({ "event": "nft_burn", "data": [{ "token_ids": ["1"] }] }),
+            json!({ "event": "add_market_data", "params": { "nft_contract_id": "nft.near", "owner_id": "alice.near", "ft_token_id": "near", "price": "1" } }),
+            json!({ "event": "add_bid", "params": { "nft_contract_id": "nft.near", "bidder_id": "bob.near", "ft_token_id": "near", "price": "1" } }),
+        ];
+
+        for log in cases {
+            assert!(decode_payloads("nft.near", &log).is_err(), "{} should not decode", log);
+        }
+    }
+
+    #[test]
+    fn decode_payloads_defaults_optional_market_fields() {
+        let log = json!({
+            "event": "add_market_data",
+            "params": { "nft_contract_id": "nft.near", "owner_id": "alice.near", "approval_id": 3, "ft_token_id": "near", "price": "100" },
+        });
+
+        match decode_payloads("market.near", &log).unwrap().as_slice() {
+            [EventPayload::AddMarketData(listing)] => {
+                assert_eq!(listing.token_id, "None");
+                assert_eq!(listing.started_at, "0");
+                assert!(!listing.is_auction);
+            }
+            _ => panic!("Expected one add_market_data"),
+        }
+    }
+
+    #[test]
+    fn api_path_is_missing_for_burns_only() {
+        let burn = json!({ "event": "nft_burn", "data": [{ "owner_id": "alice.near", "token_ids": ["1"] }] });
+        let transfer = json!({ "event": "nft_transfer", "data": [{ "old_owner_id": "alice.near", "new_owner_id": "bob.near", "token_ids": ["1"] }] });
+
+        assert_eq!(decode_payloads("nft.near", &burn).unwrap()[0].api_path(), None);
+        assert_eq!(decode_payloads("nft.near", &transfer).unwrap()[0].api_path(), Some("transfer_tokens"));
+    }
+
+    #[test]
+    fn sort_key_orders_entries_numerically() {
+        let log = json!({ "event": "nft_mint", "data": [{ "owner_id": "alice.near", "token_ids": ["1"] }] });
+        let payload = decode_payloads("nft.near", &log).unwrap().remove(0);
+        let event = |entry_index: u64| IndexedEvent {
+            event_id: format!("receipt:0:{}", entry_index),
+            contract_id: "nft.near".to_string(),
+            receipt_id: "receipt".to_string(),
+            block_height: 7,
+            block_timestamp: 0,
+            shard_id: 0,
+            outcome_index: 0,
+            log_index: 0,
+            payload: payload.clone(),
+        };
+
+        assert_eq!(event(10).entry_index(), 10);
+        assert!(event(2).sort_key() < event(10).sort_key());
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use actix_web::{ web, HttpResponse };
+use async_graphql::{ ComplexObject, Context, EmptyMutation, EmptySubscription, Enum, Object, Result, Schema, SimpleObject };
+use async_graphql::dataloader::{ DataLoader, Loader };
+use async_graphql::http::{ playground_source, GraphQLPlaygroundConfig };
+use async_trait::async_trait;
+use bson::{ Bson, doc, document::Document };
+use mongodb::{ Collection, Database, options::FindOptions };
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use tokio_stream::StreamExt;
+use tracing::warn;
+
+use crate::auth::{ RequireScope, Scope };
+use crate::http_server::AppState;
+use crate::projections;
+
+const DEFAULT_LIST_SIZE: i32 = 20;
+const MAX_LIST_SIZE: i32 = 100;
+/// The schema is served without a token on the public listener and its types nest
+/// without end (`token { listing { token { ... } } }`), so queries are bounded.
+const MAX_QUERY_DEPTH: usize = 8;
+/// List fields count as many times their items as they may return, see `list_size`.
+const MAX_QUERY_COMPLEXITY: usize = 1000;
+
+pub type MarketSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+pub fn build_schema(database: Database) -> MarketSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .data(database)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .limit_complexity(MAX_QUERY_COMPLEXITY)
+        .finish()
+}
+
+#[derive(SimpleObject, Deserialize, Clone)]
+#[graphql(complex)]
+pub struct Token {
+    pub contract_id: String,
+    pub token_id: String,
+    pub owner_id: String,
+    pub minted_at: Option<i64>,
+    pub last_transfer: Option<i64>,
+    pub burned: bool,
+}
+
+#[derive(SimpleObject, Deserialize, Clone)]
+#[graphql(complex)]
+pub struct Listing {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub market_contract_id: String,
+    pub nft_contract_id: String,
+    pub token_id: String,
+    pub owner_id: String,
+    pub approval_id: i64,
+    pub ft_token_id: String,
+    pub price: String,
+    pub started_at: String,
+    pub ended_at: String,
+    pub is_auction: bool,
+    pub status: String,
+    pub listed_at: i64,
+    pub updated_at: i64,
+    pub closed_at: Option<i64>,
+    pub buyer_id: Option<String>,
+}
+
+#[derive(SimpleObject, Deserialize, Clone)]
+#[graphql(complex)]
+pub struct Offer {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub market_contract_id: String,
+    pub nft_contract_id: String,
+    pub token_id: String,
+    pub buyer_id: String,
+    pub ft_token_id: String,
+    pub price: String,
+    pub status: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub closed_at: Option<i64>,
+}
+
+#[derive(SimpleObject, Deserialize, Clone)]
+#[graphql(complex)]
+pub struct Bid {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub market_contract_id: String,
+    pub listing_id: Option<String>,
+    pub nft_contract_id: String,
+    pub token_id: String,
+    pub bidder_id: String,
+    pub ft_token_id: String,
+    pub price: String,
+    pub status: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub closed_at: Option<i64>,
+}
+
+#[derive(SimpleObject, Deserialize, Clone)]
+#[graphql(complex)]
+pub struct Sale {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub market_contract_id: String,
+    pub nft_contract_id: String,
+    pub token_id: String,
+    pub seller_id: String,
+    pub buyer_id: String,
+    pub ft_token_id: String,
+    pub price: String,
+    pub is_offer: bool,
+    pub block_height: i64,
+    pub block_timestamp: i64,
+}
+
+/// An NFT contract and everything indexed about it.
+pub struct NftCollection {
+    contract_id: String,
+}
+
+pub struct Account {
+    account_id: String,
+}
+
+#[derive(Enum, Copy, Clone, PartialEq, Eq)]
+pub enum ListingSort {
+    PriceAsc,
+    PriceDesc,
+    Newest,
+}
+
+impl ListingSort {
+    fn sort(&self) -> Document {
+        match self {
+            ListingSort::PriceAsc => doc! { "price_sort": 1, "_id": 1 },
+            ListingSort::PriceDesc => doc! { "price_sort": -1, "_id": -1 },
+            ListingSort::Newest => doc! { "listed_at": -1, "_id": -1 },
+        }
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+pub struct TokenKey {
+    contract_id: String,
+    token_id: String,
+}
+
+/// Open listing of a token
+#[derive(Hash, PartialEq, Eq, Clone)]
+pub struct ListingOf(TokenKey);
+/// Open offers on a token, highest first, at most as many as the second field
+#[derive(Hash, PartialEq, Eq, Clone)]
+pub struct OffersOn(TokenKey, i64);
+/// Open bids on a token, highest first, at most as many as the second field
+#[derive(Hash, PartialEq, Eq, Clone)]
+pub struct BidsOn(TokenKey, i64);
+/// Sales of a token, newest first, at most as many as the second field
+#[derive(Hash, PartialEq, Eq, Clone)]
+pub struct SalesOf(TokenKey, i64);
+
+fn token_key(contract_id: &str, token_id: &str) -> TokenKey {
+    TokenKey { contract_id: contract_id.to_string(), token_id: token_id.to_string() }
+}
+
+fn list_size(first: Option<i32>) -> i64 {
+    first.unwrap_or(DEFAULT_LIST_SIZE).max(0).min(MAX_LIST_SIZE) as i64
+}
+
+/// Records that do not match their type are logged and left out rather than failing
+/// the whole query.
+fn read_record<T: DeserializeOwned>(collection_name: &str, document: Document) -> Option<T> {
+    let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+
+    match serde_json::from_value(Bson::Document(document).into_relaxed_extjson()) {
+        Ok(record) => Some(record),
+        Err(err) => {
+            warn!(collection = collection_name, id = %id, "Skipping unreadable record: {}", err);
+            None
+        }
+    }
+}
+
+async fn find_many<T: DeserializeOwned>(database: &Database, collection_name: &str, filter: Document, sort: Document, limit: Option<i64>) -> std::result::Result<Vec<T>, String> {
+    let collection: Collection<Document> = database.collection(collection_name);
+    let options = FindOptions::builder().sort(sort).limit(limit).build();
+    let mut cursor = collection.find(filter, options).await.map_err(|err| err.to_string())?;
+    let mut records = vec![];
+
+    while let Some(document) = cursor.next().await {
+        records.extend(read_record(collection_name, document.map_err(|err| err.to_string())?));
+    }
+
+    Ok(records)
+}
+
+/// Filter matching any of the given tokens, with the token fields named as in `collection`.
+fn tokens_filter(keys: &[TokenKey], contract_field: &str) -> Document {
+    let tokens = keys.iter()
+        .map(|key| Bson::Document(doc! { contract_field: key.contract_id.clone(), "token_id": key.token_id.clone() }))
+        .collect::<Vec<_>>();
+
+    doc! { "$or": tokens }
+}
+
+/// Batches the per-token lookups of one GraphQL request into a query per collection.
+pub struct ProjectionLoader {
+    database: Database,
+}
+
+#[async_trait]
+impl Loader<TokenKey> for ProjectionLoader {
+    type Value = Token;
+    type Error = String;
+
+    async fn load(&self, keys: &[TokenKey]) -> std::result::Result<HashMap<TokenKey, Token>, String> {
+        let tokens: Vec<Token> = find_many(&self.database, "tokens", tokens_filter(keys, "contract_id"), doc! { "_id": 1 }, None).await?;
+        Ok(tokens.into_iter().map(|token| (token_key(&token.contract_id, &token.token_id), token)).collect())
+    }
+}
+
+#[async_trait]
+impl Loader<ListingOf> for ProjectionLoader {
+    type Value = Listing;
+    type Error = String;
+
+    /// A token has at most one open listing, so the batch is bounded by the number of keys.
+    async fn load(&self, keys: &[ListingOf]) -> std::result::Result<HashMap<ListingOf, Listing>, String> {
+        let keys = keys.iter().map(|key| key.0.clone()).collect::<Vec<_>>();
+        let filter = projections::open_filter(tokens_filter(&keys, "nft_contract_id"));
+        let listings: Vec<Listing> = find_many(&self.database, "listings", filter, doc! { "listed_at": -1 }, None).await?;
+
+        let mut latest = HashMap::new();
+        for listing in listings {
+            latest.entry(ListingOf(token_key(&listing.nft_contract_id, &listing.token_id))).or_insert(listing);
+        }
+
+        Ok(latest)
+    }
+}
+
+impl ProjectionLoader {
+    /// Records of many tokens in one aggregation: sorted, grouped per token and sliced to
+    /// the largest limit of the batch by MongoDB, then cut to each key's own limit.
+    async fn load_limited<K, T>(&self, collection_name: &str, keys: &[K], key_of: impl Fn(&K) -> (&TokenKey, i64), open_only: bool, sort: Document) -> std::result::Result<HashMap<K, Vec<T>>, String>
+    where
+        K: std::hash::Hash + Eq + Clone,
+        T: DeserializeOwned + Clone,
+    {
+        let tokens = keys.iter().map(|key| key_of(key).0.clone()).collect::<Vec<_>>();
+        let max_limit = keys.iter().map(|key| key_of(key).1).max().unwrap_or(0);
+        let mut filter = tokens_filter(&tokens, "nft_contract_id");
+        if open_only {
+            filter = projections::open_filter(filter);
+        }
+
+        let pipeline = vec![
+            doc! { "$match": filter },
+            doc! { "$sort": sort },
+            doc! { "$group": { "_id": { "contract_id": "$nft_contract_id", "token_id": "$token_id" }, "records": { "$push": "$$ROOT" } } },
+            doc! { "$project": { "records": { "$slice": ["$records", max_limit] } } },
+        ];
+        let collection: Collection<Document> = self.database.collection(collection_name);
+        let mut cursor = collection.aggregate(pipeline, None).await.map_err(|err| err.to_string())?;
+
+        let mut grouped: HashMap<TokenKey, Vec<T>> = HashMap::new();
+        while let Some(group) = cursor.next().await {
+            let mut group = group.map_err(|err| err.to_string())?;
+            let token = match group.get_document("_id") {
+                Ok(id) => token_key(id.get_str("contract_id").unwrap_or_default(), id.get_str("token_id").unwrap_or_default()),
+                Err(_) => continue,
+            };
+            let records = match group.remove("records") {
+                Some(Bson::Array(records)) => records,
+                _ => vec![],
+            };
+            let records = records.into_iter()
+                .filter_map(|record| match record {
+                    Bson::Document(document) => read_record(collection_name, document),
+                    _ => None,
+                })
+                .collect();
+
+            grouped.insert(token, records);
+        }
+
+        Ok(keys.iter().filter_map(|key| {
+            let (token, limit) = key_of(key);
+            let records = grouped.get(token)?.iter().take(limit as usize).cloned().collect();
+            Some((key.clone(), records))
+        }).collect())
+    }
+}
+
+#[async_trait]
+impl Loader<OffersOn> for ProjectionLoader {
+    type Value = Vec<Offer>;
+    type Error = String;
+
+    async fn load(&self, keys: &[OffersOn]) -> std::result::Result<HashMap<OffersOn, Vec<Offer>>, String> {
+        self.load_limited("offers", keys, |key| (&key.0, key.1), true, doc! { "price_sort": -1 }).await
+    }
+}
+
+#[async_trait]
+impl Loader<BidsOn> for ProjectionLoader {
+    type Value = Vec<Bid>;
+    type Error = String;
+
+    async fn load(&self, keys: &[BidsOn]) -> std::result::Result<HashMap<BidsOn, Vec<Bid>>, String> {
+        self.load_limited("bids", keys, |key| (&key.0, key.1), true, doc! { "price_sort": -1 }).await
+    }
+}
+
+#[async_trait]
+impl Loader<SalesOf> for ProjectionLoader {
+    type Value = Vec<Sale>;
+    type Error = String;
+
+    async fn load(&self, keys: &[SalesOf]) -> std::result::Result<HashMap<SalesOf, Vec<Sale>>, String> {
+        self.load_limited("sales", keys, |key| (&key.0, key.1), false, doc! { "block_height": -1 }).await
+    }
+}
+
+fn loader<'a>(ctx: &'a Context<'_>) -> &'a DataLoader<ProjectionLoader> {
+    ctx.data_unchecked::<DataLoader<ProjectionLoader>>()
+}
+
+fn database<'a>(ctx: &'a Context<'_>) -> &'a Database {
+    ctx.data_unchecked::<Database>()
+}
+
+#[ComplexObject]
+impl Token {
+    async fn owner(&self) -> Account {
+        Account { account_id: self.owner_id.clone() }
+    }
+
+    async fn collection(&self) -> NftCollection {
+        NftCollection { contract_id: self.contract_id.clone() }
+    }
+
+    /// The open listing of the token, if any
+    async fn listing(&self, ctx: &Context<'_>) -> Result<Option<Listing>> {
+        Ok(loader(ctx).load_one(ListingOf(token_key(&self.contract_id, &self.token_id))).await?)
+    }
+
+    async fn highest_offer(&self, ctx: &Context<'_>) -> Result<Option<Offer>> {
+        let offers = loader(ctx).load_one(OffersOn(token_key(&self.contract_id, &self.token_id), 1)).await?;
+        Ok(offers.and_then(|offers| offers.into_iter().next()))
+    }
+
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn offers(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Offer>> {
+        let offers = loader(ctx).load_one(OffersOn(token_key(&self.contract_id, &self.token_id), list_size(first))).await?;
+        Ok(offers.unwrap_or_default())
+    }
+
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn bids(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Bid>> {
+        let bids = loader(ctx).load_one(BidsOn(token_key(&self.contract_id, &self.token_id), list_size(first))).await?;
+        Ok(bids.unwrap_or_default())
+    }
+
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn sales(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Sale>> {
+        let sales = loader(ctx).load_one(SalesOf(token_key(&self.contract_id, &self.token_id), list_size(first))).await?;
+        Ok(sales.unwrap_or_default())
+    }
+}
+
+#[ComplexObject]
+impl Listing {
+    async fn token(&self, ctx: &Context<'_>) -> Result<Option<Token>> {
+        Ok(loader(ctx).load_one(token_key(&self.nft_contract_id, &self.token_id)).await?)
+    }
+}
+
+#[ComplexObject]
+impl Offer {
+    async fn token(&self, ctx: &Context<'_>) -> Result<Option<Token>> {
+        Ok(loader(ctx).load_one(token_key(&self.nft_contract_id, &self.token_id)).await?)
+    }
+}
+
+#[ComplexObject]
+impl Bid {
+    async fn token(&self, ctx: &Context<'_>) -> Result<Option<Token>> {
+        Ok(loader(ctx).load_one(token_key(&self.nft_contract_id, &self.token_id)).await?)
+    }
+}
+
+#[ComplexObject]
+impl Sale {
+    async fn token(&self, ctx: &Context<'_>) -> Result<Option<Token>> {
+        Ok(loader(ctx).load_one(token_key(&self.nft_contract_id, &self.token_id)).await?)
+    }
+}
+
+#[Object]
+impl NftCollection {
+    async fn contract_id(&self) -> &str {
+        &self.contract_id
+    }
+
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn tokens(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Token>> {
+        let filter = doc! { "contract_id": self.contract_id.clone(), "burned": false };
+        Ok(find_many(database(ctx), "tokens", filter, doc! { "token_id": 1 }, Some(list_size(first))).await?)
+    }
+
+    /// Open listings, cheapest first unless `sort` says otherwise
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn listings(&self, ctx: &Context<'_>, first: Option<i32>, sort: Option<ListingSort>) -> Result<Vec<Listing>> {
+        let filter = projections::open_filter(doc! { "nft_contract_id": self.contract_id.clone() });
+        let sort = sort.unwrap_or(ListingSort::PriceAsc).sort();
+        Ok(find_many(database(ctx), "listings", filter, sort, Some(list_size(first))).await?)
+    }
+
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn sales(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Sale>> {
+        let filter = doc! { "nft_contract_id": self.contract_id.clone() };
+        Ok(find_many(database(ctx), "sales", filter, doc! { "block_height": -1 }, Some(list_size(first))).await?)
+    }
+}
+
+#[Object]
+impl Account {
+    async fn account_id(&self) -> &str {
+        &self.account_id
+    }
+
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn tokens(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Token>> {
+        let filter = doc! { "owner_id": self.account_id.clone(), "burned": false };
+        Ok(find_many(database(ctx), "tokens", filter, doc! { "token_id": 1 }, Some(list_size(first))).await?)
+    }
+
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn listings(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Listing>> {
+        let filter = projections::open_filter(doc! { "owner_id": self.account_id.clone() });
+        Ok(find_many(database(ctx), "listings", filter, doc! { "listed_at": -1 }, Some(list_size(first))).await?)
+    }
+
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn offers(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Offer>> {
+        let filter = projections::open_filter(doc! { "buyer_id": self.account_id.clone() });
+        Ok(find_many(database(ctx), "offers", filter, doc! { "created_at": -1 }, Some(list_size(first))).await?)
+    }
+
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn bids(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Bid>> {
+        let filter = projections::open_filter(doc! { "bidder_id": self.account_id.clone() });
+        Ok(find_many(database(ctx), "bids", filter, doc! { "created_at": -1 }, Some(list_size(first))).await?)
+    }
+
+    /// Sales the account bought or sold in, newest first
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn sales(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Sale>> {
+        let filter = doc! { "$or": [{ "seller_id": self.account_id.clone() }, { "buyer_id": self.account_id.clone() }] };
+        Ok(find_many(database(ctx), "sales", filter, doc! { "block_height": -1 }, Some(list_size(first))).await?)
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn token(&self, ctx: &Context<'_>, contract_id: String, token_id: String) -> Result<Option<Token>> {
+        Ok(loader(ctx).load_one(TokenKey { contract_id, token_id }).await?)
+    }
+
+    async fn collection(&self, contract_id: String) -> NftCollection {
+        NftCollection { contract_id }
+    }
+
+    async fn account(&self, account_id: String) -> Account {
+        Account { account_id }
+    }
+
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn listings(&self, ctx: &Context<'_>, contract_id: String, first: Option<i32>, sort: Option<ListingSort>) -> Result<Vec<Listing>> {
+        NftCollection { contract_id }.listings(ctx, first, sort).await
+    }
+
+    /// Most recent sales across every watched market
+    #[graphql(complexity = "list_size(first) as usize * child_complexity")]
+    async fn sales(&self, ctx: &Context<'_>, first: Option<i32>) -> Result<Vec<Sale>> {
+        Ok(find_many(database(ctx), "sales", doc! {}, doc! { "block_height": -1 }, Some(list_size(first))).await?)
+    }
+}
+
+/// Every request gets its own loader, so batched lookups are never served from another request's cache.
+async fn graphql(data: web::Data<AppState>, request: web::Json<async_graphql::Request>) -> HttpResponse {
+    let loader = DataLoader::new(ProjectionLoader { database: data.database.clone() });
+    let response = data.graphql_schema.execute(request.into_inner().data(loader)).await;
+
+    HttpResponse::Ok().json(response)
+}
+
+async fn playground() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(playground_source(GraphQLPlaygroundConfig::new("/graphql")))
+}
+
+/// GraphQL route for the public listener, served without a token.
+pub fn configure_public(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/graphql")
+            .route(web::post().to(graphql))
+            .route(web::get().to(playground))
+    );
+}
+
+/// GraphQL route for the admin listener, requiring the `read` scope.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/graphql")
+            .wrap(RequireScope(Scope::Read))
+            .route(web::post().to(graphql))
+            .route(web::get().to(playground))
+    );
+}
@@ -0,0 +1,216 @@
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::Duration;
+use actix::Addr;
+use actix_web::{ web, HttpResponse };
+use bson::doc;
+use chrono::{ DateTime, TimeZone, Utc };
+use mongodb::Database;
+use near_client::{ GetBlock, ViewClientActor };
+use serde::{ Serialize, Deserialize };
+use tracing::warn;
+
+use crate::http_server::AppState;
+use crate::metrics;
+use crate::sinks::SinkRouter;
+
+pub const VIEW_CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Height of the node's latest block, as seen by the view client.
+pub async fn latest_node_height(view_client: &Addr<ViewClientActor>) -> Result<u64, String> {
+    let _timer = metrics::VIEW_CLIENT_LATENCY.start_timer();
+
+    match view_client.send(GetBlock::latest()).timeout(VIEW_CLIENT_TIMEOUT).await {
+        Ok(Ok(block)) => Ok(block.header.height),
+        Ok(Err(err)) => Err(format!("{:?}", err)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Last block the indexer finished processing, shared between the block loop and the health endpoints.
+#[derive(Clone, Default)]
+pub struct SyncStatus {
+    last_height: Arc<AtomicU64>,
+    /// Nanoseconds, as in the block header
+    last_block_timestamp: Arc<AtomicU64>,
+}
+
+impl SyncStatus {
+    pub fn record_block(&self, block_height: u64, block_timestamp: u64) {
+        self.last_height.store(block_height, Ordering::SeqCst);
+        self.last_block_timestamp.store(block_timestamp, Ordering::SeqCst);
+    }
+
+    pub fn last_height(&self) -> Option<u64> {
+        match self.last_height.load(Ordering::SeqCst) {
+            0 => None,
+            height => Some(height),
+        }
+    }
+
+    pub fn last_block_timestamp(&self) -> Option<DateTime<Utc>> {
+        match self.last_block_timestamp.load(Ordering::SeqCst) {
+            0 => None,
+            nanos => Some(Utc.timestamp_nanos(nanos as i64)),
+        }
+    }
+}
+
+/// Limits past which `/readyz` reports the capacitor as not ready.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadinessThresholds {
+    /// Blocks the last processed block may trail the node's latest block by
+    pub max_lag_blocks: u64,
+    /// Age of the last processed block
+    pub max_block_age_secs: i64,
+    pub max_outbox_depth: usize,
+    /// Time since the last successful delivery, unchecked unless set
+    pub max_delivery_idle_secs: Option<i64>,
+}
+
+impl Default for ReadinessThresholds {
+    fn default() -> Self {
+        Self {
+            max_lag_blocks: 50,
+            max_block_age_secs: 120,
+            max_outbox_depth: 10_000,
+            max_delivery_idle_secs: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub ready: bool,
+    /// Names of the failed readiness checks
+    pub failing: Vec<String>,
+    pub last_height: Option<u64>,
+    pub last_block_timestamp: Option<String>,
+    pub node_height: Option<u64>,
+    pub lag_blocks: Option<u64>,
+    pub mongodb: String,
+    pub outbox_depth: usize,
+    pub last_delivery_at: Option<String>,
+    pub seconds_since_last_delivery: Option<i64>,
+}
+
+#[derive(Clone)]
+pub struct Health {
+    sync_status: SyncStatus,
+    view_client: Addr<ViewClientActor>,
+    database: Database,
+    sink_router: SinkRouter,
+    thresholds: ReadinessThresholds,
+}
+
+impl Health {
+    pub fn new(sync_status: SyncStatus, view_client: Addr<ViewClientActor>, database: Database, sink_router: SinkRouter, thresholds: ReadinessThresholds) -> Self {
+        Self { sync_status, view_client, database, sink_router, thresholds }
+    }
+
+    async fn ping_database(&self) -> Result<(), String> {
+        self.database.run_command(doc! { "ping": 1 }, None).await.map(|_| ()).map_err(|err| err.to_string())
+    }
+
+    pub fn last_height(&self) -> Option<u64> {
+        self.sync_status.last_height()
+    }
+
+    /// Blocks the last processed block trails the node's latest block by, once a block was
+    /// processed and the node answers.
+    pub async fn block_lag(&self) -> Option<u64> {
+        let last_height = self.sync_status.last_height()?;
+
+        match latest_node_height(&self.view_client).await {
+            Ok(node_height) => Some(node_height.saturating_sub(last_height)),
+            Err(err) => {
+                warn!("Failed to query the latest block from the view client: {}", err);
+                None
+            }
+        }
+    }
+
+    pub async fn report(&self) -> HealthReport {
+        let thresholds = &self.thresholds;
+        let now = Utc::now();
+        let mut failing = vec![];
+
+        let last_height = self.sync_status.last_height();
+        let last_block_timestamp = self.sync_status.last_block_timestamp();
+        match last_block_timestamp {
+            Some(timestamp) if (now - timestamp).num_seconds() <= thresholds.max_block_age_secs => (),
+            _ => failing.push("block_age".to_string()),
+        }
+
+        let node_height = match latest_node_height(&self.view_client).await {
+            Ok(height) => Some(height),
+            Err(err) => {
+                warn!("Failed to query the latest block from the view client: {}", err);
+                failing.push("node".to_string());
+                None
+            }
+        };
+        let lag_blocks = match (node_height, last_height) {
+            (Some(node_height), Some(last_height)) => Some(node_height.saturating_sub(last_height)),
+            _ => None,
+        };
+        if lag_blocks.map_or(true, |lag| lag > thresholds.max_lag_blocks) {
+            failing.push("lag".to_string());
+        }
+
+        let mongodb = match self.ping_database().await {
+            Ok(()) => "ok".to_string(),
+            Err(err) => {
+                failing.push("mongodb".to_string());
+                err
+            }
+        };
+
+        let outbox_depth = self.sink_router.outbox_depth();
+        if outbox_depth > thresholds.max_outbox_depth {
+            failing.push("outbox_depth".to_string());
+        }
+
+        let last_delivery_at = self.sink_router.last_delivery_at();
+        let seconds_since_last_delivery = last_delivery_at.map(|delivered_at| (now - delivered_at).num_seconds());
+        if let Some(max_delivery_idle_secs) = thresholds.max_delivery_idle_secs {
+            if seconds_since_last_delivery.map_or(true, |idle| idle > max_delivery_idle_secs) {
+                failing.push("delivery".to_string());
+            }
+        }
+
+        HealthReport {
+            ready: failing.is_empty(),
+            failing,
+            last_height,
+            last_block_timestamp: last_block_timestamp.map(|timestamp| timestamp.to_rfc3339()),
+            node_height,
+            lag_blocks,
+            mongodb,
+            outbox_depth,
+            last_delivery_at: last_delivery_at.map(|delivered_at| delivered_at.to_rfc3339()),
+            seconds_since_last_delivery,
+        }
+    }
+}
+
+/// Liveness: answers 200 as long as the server runs, with the same report as `/readyz`.
+async fn healthz(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.health.report().await)
+}
+
+/// Readiness: 503 while any threshold is exceeded or a dependency is unreachable.
+async fn readyz(data: web::Data<AppState>) -> HttpResponse {
+    let report = data.health.report().await;
+
+    match report.ready {
+        true => HttpResponse::Ok().json(report),
+        false => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz));
+}
@@ -1,48 +1,256 @@
-
 use std::sync::{ Arc, Mutex };
 use crate::Capacitor;
-use actix_web::{ web, App, HttpServer, HttpRequest, HttpResponse };
-use std::env;
+use crate::admin_api::{ self, api_error };
+use crate::backfill::Backfill;
+use crate::contracts::{ self, ContractKind, ContractUpdate, WatchedContract };
+use crate::discovery::Discovery;
+use crate::reload::Reloader;
+use crate::sinks::SinkRouter;
+use crate::health::{ self, Health };
+use crate::metrics;
+use crate::query_api;
+use crate::stream::{ self, EventBroadcast };
+use crate::event_log::EventLog;
+use crate::graphql::{ self, MarketSchema };
+use crate::webhooks::WebhookStore;
+use mongodb::Database;
+use crate::auth::{ ApiTokens, RequireScope, Scope };
+use actix_web::{ web, App, HttpServer, HttpRequest, HttpResponse, http::StatusCode };
 use qstring::{ QString };
+use rustls::{ NoClientAuth, ServerConfig };
+use rustls::internal::pemfile::{ certs, pkcs8_private_keys, rsa_private_keys };
+use serde::Deserialize;
+use tracing::info;
+use std::fs::File;
+use std::io::BufReader;
 
-struct AppState {
-    capacitor_ins: Arc<Mutex<Capacitor>>,
+pub(crate) struct AppState {
+    pub capacitor_ins: Arc<Mutex<Capacitor>>,
+    pub discovery: Discovery,
+    pub sink_router: SinkRouter,
+    pub health: Health,
+    pub database: Database,
+    pub event_log: EventLog,
+    pub event_broadcast: EventBroadcast,
+    pub graphql_schema: MarketSchema,
+    pub webhooks: WebhookStore,
+    pub backfill: Backfill,
+    pub reloader: Reloader,
 }
 
-async fn handle_post_add_account(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
-    let api_token = env::var("API_TOKEN").expect("API_TOKEN is required to be defined in the .env file");
-    let mut capacitor_ins = data.capacitor_ins.lock().unwrap();
-    let query_string = QString::from(req.query_string());
-    let req_token = match query_string.get("token") {
-        Some(token) => token,
-        None => return HttpResponse::BadRequest().body("`token` is a required parameter"),
-    };
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl TlsConfig {
+    /// Reads the PEM certificate chain and its PKCS#8 or RSA private key.
+    fn server_config(&self) -> ServerConfig {
+        let open = |path: &str| BufReader::new(File::open(path).unwrap_or_else(|err| panic!("Could not open {}: {}", path, err)));
+
+        let cert_chain = certs(&mut open(&self.cert_path)).unwrap_or_else(|_| panic!("Malformed certificate in {}", self.cert_path));
+        let mut keys = pkcs8_private_keys(&mut open(&self.key_path)).unwrap_or_default();
+        if keys.is_empty() {
+            keys = rsa_private_keys(&mut open(&self.key_path)).unwrap_or_default();
+        }
+        let key = keys.into_iter().next().unwrap_or_else(|| panic!("No private key found in {}", self.key_path));
 
-    if req_token != api_token {
-        return HttpResponse::Forbidden().body("Api token did not match");
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.set_single_cert(cert_chain, key).expect("Certificate and private key do not match");
+        config
     }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: String,
+    pub port: u16,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1".to_string(),
+            port: 0,
+            tls: None,
+        }
+    }
+}
+
+impl ListenerConfig {
+    pub fn socket_address(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+}
+
+/// The private admin listener, plus an optional public one serving only read routes.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub admin: ListenerConfig,
+    pub public: Option<ListenerConfig>,
+    /// Worker threads per listener, defaults to the number of CPU cores
+    pub workers: Option<usize>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            admin: ListenerConfig { port: 3333, ..ListenerConfig::default() },
+            public: None,
+            workers: None,
+        }
+    }
+}
+
+fn list_param(query_string: &QString, name: &str) -> Option<Vec<String>> {
+    query_string.get(name).map(|list| list.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect())
+}
+
+/// Reads the optional watched contract fields shared by adding and updating.
+fn contract_update_params(query_string: &QString) -> Result<ContractUpdate, HttpResponse> {
+    let kind = match query_string.get("kind") {
+        Some(kind) => Some(kind.parse::<ContractKind>().map_err(|err| HttpResponse::BadRequest().body(err))?),
+        None => None,
+    };
+    let start_height = match query_string.get("start_height") {
+        Some(start_height) => Some(start_height.parse::<u64>().map_err(|_| HttpResponse::BadRequest().body("`start_height` must be a block height"))?),
+        None => None,
+    };
+    let enabled = match query_string.get("enabled") {
+        Some(enabled) => Some(enabled.parse::<bool>().map_err(|_| HttpResponse::BadRequest().body("`enabled` must be true or false"))?),
+        None => None,
+    };
+    let factory = match query_string.get("factory") {
+        Some(factory) => Some(factory.parse::<bool>().map_err(|_| HttpResponse::BadRequest().body("`factory` must be true or false"))?),
+        None => None,
+    };
+
+    Ok(ContractUpdate {
+        label: query_string.get("label").map(str::to_string),
+        enabled,
+        kind,
+        start_height,
+        sinks: list_param(query_string, "sinks"),
+        events: list_param(query_string, "events"),
+        factory,
+    })
+}
+
+/// Adds a watched contract. `backfill_from` also starts a backfill job from that height,
+/// which needs a token with the `backfill` scope.
+async fn handle_post_add_account(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let query_string = QString::from(req.query_string());
 
     let req_account_id = match query_string.get("account_id") {
         Some(account_id) => account_id,
         None => return HttpResponse::BadRequest().body("`account_id` is a required parameter"),
     };
+    if let Err(err) = contracts::validate_account_id(req_account_id) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let update = match contract_update_params(&query_string) {
+        Ok(update) => update,
+        Err(response) => return response,
+    };
+
+    let backfill_from = match query_string.get("backfill_from").map(str::parse::<u64>) {
+        Some(Ok(backfill_from)) => Some(backfill_from),
+        Some(Err(_)) => return HttpResponse::BadRequest().body("`backfill_from` must be a block height"),
+        None => None,
+    };
+    if backfill_from.is_some() && !admin_api::has_scope(&req, Scope::Backfill) {
+        return api_error(StatusCode::FORBIDDEN, "forbidden", "Token lacks the `backfill` scope");
+    }
+
+    let mut contract = WatchedContract::new(req_account_id.to_string(), &admin_api::token_name(&req));
+    contract.apply(&update);
 
-    capacitor_ins.add_account_id(req_account_id.to_string()).await;
+    let added = Capacitor::add_account_id(&data.capacitor_ins, contract).await;
+    let message = match added {
+        Ok(true) => format!("Account '{}' was added to the database", &req_account_id),
+        Ok(false) => format!("Account '{}' is already watched", &req_account_id),
+        Err(err) => return HttpResponse::InternalServerError().body(err),
+    };
 
-    return HttpResponse::Ok().body(format!("Account '{}' was added to the database", &req_account_id));
+    match backfill_from {
+        Some(from_height) => match admin_api::start_backfill(&data, &req, req_account_id, from_height, None).await {
+            Ok(job) => HttpResponse::Ok().body(format!("{}, backfilling it from block {} as job {}", message, from_height, job.id)),
+            Err(response) => response,
+        },
+        None => HttpResponse::Ok().body(message),
+    }
 }
 
-pub async fn start_http_server(capacitor_ins: Arc<Mutex<Capacitor>>) {
+pub async fn start_http_server(config: HttpConfig, capacitor_ins: Arc<Mutex<Capacitor>>, discovery: Discovery, sink_router: SinkRouter, api_tokens: ApiTokens, health: Health, database: Database, event_log: EventLog, event_broadcast: EventBroadcast, webhooks: WebhookStore, backfill: Backfill, reloader: Reloader) {
     let state = web::Data::new(AppState {
         capacitor_ins,
+        discovery,
+        sink_router,
+        health,
+        graphql_schema: graphql::build_schema(database.clone()),
+        database,
+        event_log,
+        event_broadcast,
+        webhooks,
+        backfill,
+        reloader,
     });
+    let api_tokens = web::Data::new(api_tokens);
+
+    if let Some(public) = config.public.clone() {
+        let public_state = state.clone();
+        let mut server = HttpServer::new(move || {
+            App::new()
+                .app_data(public_state.clone())
+                .configure(health::configure)
+                .configure(query_api::configure_public)
+                .configure(stream::configure_public)
+                .configure(graphql::configure_public)
+        });
+        if let Some(workers) = config.workers {
+            server = server.workers(workers);
+        }
+        let server = match &public.tls {
+            Some(tls) => server.bind_rustls(public.socket_address(), tls.server_config()),
+            None => server.bind(public.socket_address()),
+        }.unwrap_or_else(|err| panic!("Could not run public http server on {}: {}", public.socket_address(), err));
 
-    HttpServer::new(move || {
+        info!(address = %public.socket_address(), "Public http server listening");
+        actix::spawn(async move {
+            server.run().await.expect("Failed to start public http server");
+        });
+    }
+
+    let mut server = HttpServer::new(move || {
         App::new()
             .app_data(state.clone())
-            .route("/config/add_account", web::get().to(handle_post_add_account))
-    })
-    .bind("127.0.0.1:3333").expect("Could not run http server on that port")
-    .run()
-    .await.expect("Failed to start http server");
+            .app_data(api_tokens.clone())
+            .configure(health::configure)
+            .configure(metrics::configure)
+            .service(
+                web::resource("/config/add_account")
+                    .wrap(RequireScope(Scope::ContractsWrite))
+                    .route(web::post().to(handle_post_add_account))
+            )
+            .configure(admin_api::configure)
+            .configure(query_api::configure)
+            .configure(stream::configure)
+            .configure(graphql::configure)
+    });
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+    let admin = &config.admin;
+    let server = match &admin.tls {
+        Some(tls) => server.bind_rustls(admin.socket_address(), tls.server_config()),
+        None => server.bind(admin.socket_address()),
+    }.unwrap_or_else(|err| panic!("Could not run admin http server on {}: {}", admin.socket_address(), err));
+
+    info!(address = %admin.socket_address(), "Admin http server listening");
+    server.run().await.expect("Failed to start http server");
 }
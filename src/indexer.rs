@@ -1,23 +1,97 @@
 use std::sync::{ Arc, Mutex };
+use std::collections::HashMap;
+use std::time::Duration;
 use tokio::sync::mpsc;
 use crate::Capacitor;
-use actix::Addr;
-use near_client::ViewClientActor;
+use crate::sinks::SinkRouter;
+use crate::projections::Projections;
+use crate::event_log::EventLog;
+use crate::discovery::Discovery;
+use crate::health::SyncStatus;
+use crate::metrics;
+use crate::stream::EventBroadcast;
+use tracing::{ error, info };
 
-pub async fn handle_blocks_message(capacitor_ins: Arc<Mutex<Capacitor>>, mut stream: mpsc::Receiver<near_indexer::StreamerMessage>, view_client: Addr<ViewClientActor>, public_api_root: String, signature_header: String) {    
+/// Bounds of the backoff between attempts to append a block to the event log.
+const APPEND_RETRY_MIN: Duration = Duration::from_secs(1);
+const APPEND_RETRY_MAX: Duration = Duration::from_secs(60);
+
+pub async fn handle_blocks_message(capacitor_ins: Arc<Mutex<Capacitor>>, mut stream: mpsc::Receiver<near_indexer::StreamerMessage>, event_log: EventLog, projections: Option<Projections>, discovery: Option<Discovery>, sink_router: SinkRouter, sync_status: SyncStatus, event_broadcast: EventBroadcast) {
     while let Some(streamer_message) = stream.recv().await {
-        println!("⛏ Block height {:?}", streamer_message.block.header.height);
-        let capacitor_unwrapped = capacitor_ins.lock().unwrap();
-        
-        for shard in streamer_message.shards {
-            for tx_res in shard.receipt_execution_outcomes {
-                if !capacitor_unwrapped.is_valid_receipt(&tx_res.execution_outcome) {
-                    continue;
+        let block_height = streamer_message.block.header.height;
+        info!(height = block_height, "Processing block");
+        let block_timestamp = streamer_message.block.header.timestamp;
+        let mut block_events = vec![];
+        let mut target_sinks = HashMap::new();
+        let mut deployed_contracts = vec![];
+        let mut discovered: HashMap<String, Vec<String>> = HashMap::new();
+
+        {
+            let mut capacitor_unwrapped = capacitor_ins.lock().unwrap();
+
+            for shard in streamer_message.shards {
+                for (outcome_index, tx_res) in shard.receipt_execution_outcomes.iter().enumerate() {
+                    // Watch factory deployments before processing them, the deploy
+                    // receipt usually also initializes the contract and emits events
+                    if let Some(contract) = capacitor_unwrapped.detect_factory_deploy(&tx_res.receipt, &tx_res.execution_outcome, block_height) {
+                        info!(height = block_height, contract = %contract.account_id, factory = %contract.added_by.clone().unwrap_or_default(), "Factory deployed a contract, watching it");
+                        capacitor_unwrapped.watch_deployed(contract.clone());
+                        deployed_contracts.push(contract);
+                    }
+
+                    if !capacitor_unwrapped.is_valid_receipt(&tx_res.execution_outcome, block_height) {
+                        let executor_id = tx_res.execution_outcome.outcome.executor_id.as_str();
+
+                        if discovery.is_some() && capacitor_unwrapped.matching_contract(executor_id).is_none() {
+                            let event_types = Discovery::scan_outcome(&tx_res.execution_outcome);
+                            if !event_types.is_empty() {
+                                discovered.entry(executor_id.to_string()).or_default().extend(event_types);
+                            }
+                        }
+                        continue;
+                    }
+
+                    let contract_id = tx_res.execution_outcome.outcome.executor_id.to_string();
+                    target_sinks.insert(contract_id.clone(), capacitor_unwrapped.target_sinks(&contract_id));
+
+                    block_events.extend(capacitor_unwrapped.process_outcome(&tx_res.execution_outcome, block_height, block_timestamp, shard.shard_id, outcome_index as u64));
+                }
+            }
+        }
+
+        if !deployed_contracts.is_empty() {
+            for contract in deployed_contracts {
+                if let Err(err) = Capacitor::add_account_id(&capacitor_ins, contract).await {
+                    error!(height = block_height, "{}", err);
                 }
-    
-                capacitor_unwrapped.process_outcome(tx_res.execution_outcome.outcome, view_client.clone(), public_api_root.clone(), signature_header.clone() ).await;
             }
+        }
+
+        if let Some(discovery) = &discovery {
+            if let Err(err) = discovery.record_block(block_height, &discovered).await {
+                error!(height = block_height, "Failed to record discovered contracts: {:?}", err);
+            }
+        }
 
+        // Everything downstream reads from the log, so the block waits until it is in there.
+        // Appending is idempotent, retrying never duplicates events.
+        let mut retry_delay = APPEND_RETRY_MIN;
+        while let Err(err) = event_log.append(&block_events).await {
+            error!(height = block_height, "Failed to append the block to the event log, retrying in {}s: {:?}", retry_delay.as_secs(), err);
+            tokio::time::sleep(retry_delay).await;
+            retry_delay = (retry_delay * 2).min(APPEND_RETRY_MAX);
         }
+
+        if let Some(projections) = &projections {
+            if let Err(err) = projections.apply_block(block_height, block_timestamp, &block_events).await {
+                error!(height = block_height, "Failed to apply the block to projections: {:?}", err);
+            }
+        }
+
+        sink_router.dispatch_block(&block_events, |event| target_sinks.get(&event.contract_id).cloned().flatten()).await;
+        event_broadcast.publish(&block_events);
+
+        sync_status.record_block(block_height, block_timestamp);
+        metrics::BLOCKS_PROCESSED.inc();
     }
 }
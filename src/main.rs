@@ -3,67 +3,168 @@ use clap::Parser;
 use tokio::sync::mpsc;
 use std::convert::TryFrom;
 use std::sync::{ Arc, Mutex };
-use std::env;
-use configs::{ init_logging, Opts, SubCommand };
+use configs::{ init_logging, Config, Opts, SubCommand };
 use dotenv::dotenv;
+use tracing::info;
 
 mod configs;
 mod capacitor;
 mod http_server;
+mod admin_api;
 mod indexer;
 mod database;
+mod events;
+mod sinks;
+mod projections;
+mod event_log;
+mod migrations;
+mod contracts;
+mod discovery;
+mod auth;
+mod health;
+mod metrics;
+mod query_api;
+mod stream;
+mod graphql;
+mod webhooks;
+mod backfill;
+mod doctor;
+mod reload;
+mod accounts;
+mod decode;
 
 use capacitor::Capacitor;
 use http_server::{ start_http_server };
 use indexer::{ handle_blocks_message };
 use database::{ db_connect };
+use sinks::{ load_sink_configs, SinkRouter };
+use projections::Projections;
+use event_log::EventLog;
+use discovery::Discovery;
+use auth::ApiTokens;
+use health::{ Health, SyncStatus };
+use stream::EventBroadcast;
+use webhooks::WebhookStore;
+use backfill::Backfill;
+use reload::Reloader;
 
 use near_indexer;
 use actix::Addr;
 use near_client::ViewClientActor;
 
-async fn start_process(stream: mpsc::Receiver<near_indexer::StreamerMessage>, view_client: Addr<ViewClientActor>) {
-    let public_api = env::var("PUBLIC_API").expect("PUBLIC_API is required to be defined in the .env file");
-    let signature = env::var("API_TOKEN").expect("API_TOKEN is required to be defined in the .env file");
-    let database_client = db_connect().await;
-    let mut capacitor_ins = Capacitor::new(database_client, vec![]);
+async fn start_process(stream: mpsc::Receiver<near_indexer::StreamerMessage>, view_client: Addr<ViewClientActor>, opts: Opts, config: Config) {
+    let signature = config.auth.api_token.clone().expect("auth.api_token is validated on startup");
+    let api_tokens = ApiTokens::load(&config.auth).unwrap_or_else(|err| panic!("{}", err));
+    let sink_configs = load_sink_configs(&config).unwrap_or_else(|err| panic!("{}", err));
+    let database_client = db_connect(&config.mongodb).await;
+    let mut capacitor_ins = Capacitor::new(database_client.clone(), &config.mongodb.database, config.contracts.clone());
+    migrations::run(&capacitor_ins.database()).await.unwrap_or_else(|err| panic!("{}", err));
     capacitor_ins.load().await;
+    let event_log = EventLog::new(capacitor_ins.database());
+    let discovery = Discovery::new(capacitor_ins.database());
+    let projections = config.indexing.projections.then(|| Projections::new(database_client, capacitor_ins.database()));
+    let sink_router = SinkRouter::start(sink_configs, capacitor_ins.database(), signature);
+    let webhooks = WebhookStore::new(capacitor_ins.database());
+    for subscription in webhooks.list().await.expect("Failed to load webhook subscriptions") {
+        subscription.route(&sink_router, webhooks.database());
+    }
+
+    let discovery_enabled = config.indexing.discovery;
+    let sync_status = SyncStatus::default();
+    let health = Health::new(sync_status.clone(), view_client.clone(), capacitor_ins.database(), sink_router.clone(), config.readiness.clone());
+    let database = capacitor_ins.database();
+    let event_broadcast = EventBroadcast::new();
 
     let mutex_capacitor: Mutex<Capacitor> = Mutex::new(capacitor_ins);
     let wrapped_capacitor = Arc::new(mutex_capacitor);
 
-    actix::spawn(handle_blocks_message(wrapped_capacitor.clone(), stream, view_client, public_api, signature));
-    actix::spawn(start_http_server(wrapped_capacitor.clone()));
+    let backfill = Backfill::new(database.clone(), view_client, wrapped_capacitor.clone(), event_log.clone(), sink_router.clone(), sync_status.clone(), projections.clone(), config.indexing.backfill_blocks_per_second);
+    let resumed = backfill.resume_unfinished().await.expect("Failed to resume backfill jobs");
+    if resumed > 0 {
+        info!(jobs = resumed, "Resumed backfill jobs");
+    }
+
+    let reloader = Reloader::new(opts, config.clone(), wrapped_capacitor.clone(), sink_router.clone(), database.clone());
+    actix::spawn(reloader.clone().reload_on_sighup());
+
+    actix::spawn(handle_blocks_message(wrapped_capacitor.clone(), stream, event_log.clone(), projections, discovery_enabled.then(|| discovery.clone()), sink_router.clone(), sync_status, event_broadcast.clone()));
+    actix::spawn(start_http_server(config.http, wrapped_capacitor.clone(), discovery, sink_router, api_tokens, health, database, event_log, event_broadcast, webhooks, backfill, reloader));
 }
     
 fn main() {
     // We use it to automatically search the for root certificates to perform HTTPS calls
     // (sending telemetry and downloading genesis)
-    println!("🚀 Starting flux capacitor");
     openssl_probe::init_ssl_cert_env_vars();
-    init_logging();
     dotenv().ok();
     
     let opts: Opts = Opts::parse();
-    let home_dir = opts.home_dir.unwrap_or(std::path::PathBuf::from(near_indexer::get_default_home()));
-    let start_height  = 67779380 as u64;
+    let config = Config::load(&opts).unwrap_or_else(|err| {
+        eprintln!("❌ {}", err);
+        std::process::exit(1);
+    });
+    init_logging(&config.logging, opts.debug);
+    info!("Starting flux capacitor");
+    let home_dir = config.node.home_dir();
     
     match opts.subcmd {
         SubCommand::Run => {
-            let indexer_config = near_indexer::IndexerConfig {
-                home_dir,
-                sync_mode: near_indexer::SyncModeEnum::LatestSynced,
-                await_for_node_synced: near_indexer::AwaitForNodeSyncedEnum::WaitForFullSync
-            };
+            let indexer_config = config.node.indexer_config();
             let sys = actix::System::new();
             sys.block_on(async move {
                 let indexer = near_indexer::Indexer::new(indexer_config).expect("Failed to initiate Indexer");
                 let stream = indexer.streamer();
                 let view_client = indexer.client_actors().0; //returns tuple, second is another client actor - we only care about first value
-                actix::spawn(start_process(stream, view_client));
+                actix::spawn(start_process(stream, view_client, opts, config));
             });
             sys.run().unwrap();
         }
+        SubCommand::RebuildProjections => {
+            let sys = actix::System::new();
+            sys.block_on(async move {
+                let database_client = db_connect(&config.mongodb).await;
+                let capacitor_ins = Capacitor::new(database_client.clone(), &config.mongodb.database, vec![]);
+                migrations::run(&capacitor_ins.database()).await.unwrap_or_else(|err| panic!("{}", err));
+                let event_log = EventLog::new(capacitor_ins.database());
+                let projections = Projections::new(database_client, capacitor_ins.database());
+                let replayed = projections.rebuild(&event_log).await.expect("Failed to rebuild projections");
+
+                info!(events = replayed, "Rebuilt projections");
+            });
+        }
+        SubCommand::CheckConfig => {
+            if !doctor::check_config(&config) {
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Doctor => {
+            let sys = actix::System::new();
+            let passed = sys.block_on(async move { doctor::doctor(&config).await });
+            if !passed {
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Accounts(args) => {
+            let sys = actix::System::new();
+            if let Err(err) = sys.block_on(async move { accounts::run(args.command, &config).await }) {
+                eprintln!("❌ {}", err);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Decode(args) => {
+            let input = args.input.unwrap_or_else(|| {
+                let mut input = String::new();
+                std::io::Read::read_to_string(&mut std::io::stdin(), &mut input).expect("Failed to read the input from stdin");
+                input
+            });
+            if !decode::decode(&input, &args.contract) {
+                std::process::exit(1);
+            }
+        }
+        SubCommand::HashToken => {
+            let mut token = String::new();
+            std::io::stdin().read_line(&mut token).expect("Failed to read the token from stdin");
+            println!("{}", auth::hash_token(token.trim()));
+        }
         SubCommand::Init(config) => near_indexer::init_configs(
             &home_dir,
             config.chain_id.as_ref().map(AsRef::as_ref),
@@ -0,0 +1,72 @@
+use actix_web::{ web, HttpResponse };
+use lazy_static::lazy_static;
+use prometheus::{ Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder };
+
+use crate::http_server::AppState;
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+
+    pub static ref BLOCKS_PROCESSED: IntCounter = register(
+        IntCounter::new("capacitor_blocks_processed_total", "Blocks processed by the indexer").unwrap()
+    );
+    pub static ref EVENTS: IntCounterVec = register(
+        IntCounterVec::new(Opts::new("capacitor_events_total", "Events decoded from watched contracts"), &["contract_id", "event_type"]).unwrap()
+    );
+    pub static ref PARSE_FAILURES: IntCounterVec = register(
+        IntCounterVec::new(Opts::new("capacitor_parse_failures_total", "Logs of watched contracts that could not be decoded"), &["contract_id"]).unwrap()
+    );
+    pub static ref DELIVERY_ATTEMPTS: IntCounterVec = register(
+        IntCounterVec::new(Opts::new("capacitor_delivery_attempts_total", "Delivery attempts per sink"), &["sink"]).unwrap()
+    );
+    pub static ref DELIVERY_SUCCESSES: IntCounterVec = register(
+        IntCounterVec::new(Opts::new("capacitor_delivery_successes_total", "Successful deliveries per sink"), &["sink"]).unwrap()
+    );
+    pub static ref DELIVERY_FAILURES: IntCounterVec = register(
+        IntCounterVec::new(Opts::new("capacitor_delivery_failures_total", "Failed delivery attempts per sink"), &["sink"]).unwrap()
+    );
+    pub static ref HTTP_DELIVERY_RESPONSES: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new("capacitor_http_delivery_responses_total", "Responses to HTTP deliveries per sink, `error` when no response arrived"),
+            &["sink", "status"]
+        ).unwrap()
+    );
+    pub static ref DELIVERY_LATENCY: HistogramVec = register(
+        HistogramVec::new(HistogramOpts::new("capacitor_delivery_latency_seconds", "Time taken by a single delivery attempt"), &["sink"]).unwrap()
+    );
+    pub static ref OUTBOX_DEPTH: IntGauge = register(
+        IntGauge::new("capacitor_outbox_depth", "Events queued for delivery across all sinks").unwrap()
+    );
+    pub static ref VIEW_CLIENT_LATENCY: Histogram = register(
+        Histogram::with_opts(HistogramOpts::new("capacitor_view_client_latency_seconds", "Time taken by view client queries")).unwrap()
+    );
+    pub static ref BLOCK_LAG: IntGauge = register(
+        IntGauge::new("capacitor_block_lag", "Blocks the last processed block trails the node's latest block by").unwrap()
+    );
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY.register(Box::new(collector.clone())).expect("Metric registered twice");
+    collector
+}
+
+async fn metrics(data: web::Data<AppState>) -> HttpResponse {
+    OUTBOX_DEPTH.set(data.sink_router.outbox_depth() as i64);
+    // Asked for on scrape rather than on every block, keeping the view client off the block loop
+    if let Some(lag) = data.health.block_lag().await {
+        BLOCK_LAG.set(lag as i64);
+    }
+
+    let mut buffer = vec![];
+    let encoder = TextEncoder::new();
+    if let Err(err) = encoder.encode(&REGISTRY.gather(), &mut buffer) {
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+
+    HttpResponse::Ok().content_type(encoder.format_type()).body(buffer)
+}
+
+/// Only registered on the admin listener, as sink names and queue depths describe the setup.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(metrics));
+}
@@ -0,0 +1,197 @@
+use mongodb::{ Database, Collection, options::{ FindOneOptions } };
+use bson::{ Bson, doc, document::Document };
+use tokio_stream::StreamExt;
+use tracing::info;
+
+const MIGRATIONS_COLLECTION: &str = "schema_migrations";
+
+enum Step {
+    /// Keeps the oldest document for every value of `key` and deletes the rest
+    DropDuplicates { collection: &'static str, key: &'static str },
+    CreateIndex { collection: &'static str, name: &'static str, keys: Document, unique: bool },
+    /// Lets MongoDB delete documents once `field`, a date, is `expire_after_secs` in the past
+    CreateTtlIndex { collection: &'static str, name: &'static str, field: &'static str, expire_after_secs: i64 },
+}
+
+struct Migration {
+    version: i64,
+    description: &'static str,
+    steps: Vec<Step>,
+}
+
+fn migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            description: "unique account_id for watched contracts",
+            steps: vec![
+                Step::DropDuplicates { collection: "allowed_account_ids", key: "account_id" },
+                Step::CreateIndex { collection: "allowed_account_ids", name: "account_id_unique", keys: doc! { "account_id": 1 }, unique: true },
+            ],
+        },
+        Migration {
+            version: 2,
+            description: "event log and projection indexes",
+            steps: vec![
+                Step::CreateIndex { collection: "events", name: "chain_order", keys: doc! { "block_height": 1, "shard_id": 1, "outcome_index": 1, "log_index": 1 }, unique: false },
+                Step::CreateIndex { collection: "events", name: "contract_id", keys: doc! { "contract_id": 1, "block_height": 1 }, unique: false },
+                Step::CreateIndex { collection: "tokens", name: "contract_token_unique", keys: doc! { "contract_id": 1, "token_id": 1 }, unique: true },
+                Step::CreateIndex { collection: "tokens", name: "owner_id", keys: doc! { "owner_id": 1 }, unique: false },
+                Step::CreateIndex { collection: "listings", name: "token_status", keys: doc! { "nft_contract_id": 1, "token_id": 1, "status": 1 }, unique: false },
+                Step::CreateIndex { collection: "offers", name: "token_buyer_status", keys: doc! { "nft_contract_id": 1, "token_id": 1, "buyer_id": 1, "status": 1 }, unique: false },
+                Step::CreateIndex { collection: "bids", name: "token_bidder_status", keys: doc! { "nft_contract_id": 1, "token_id": 1, "bidder_id": 1, "status": 1 }, unique: false },
+                Step::CreateIndex { collection: "sales", name: "token_height", keys: doc! { "nft_contract_id": 1, "token_id": 1, "block_height": -1 }, unique: false },
+            ],
+        },
+        Migration {
+            version: 3,
+            description: "discovered contracts index",
+            steps: vec![
+                Step::CreateIndex { collection: "discovered_contracts", name: "promoted_event_count", keys: doc! { "promoted": 1, "event_count": -1 }, unique: false },
+            ],
+        },
+        Migration {
+            version: 4,
+            description: "query api indexes",
+            steps: vec![
+                Step::CreateIndex { collection: "tokens", name: "owner_token", keys: doc! { "owner_id": 1, "burned": 1, "token_id": 1 }, unique: false },
+                Step::CreateIndex { collection: "listings", name: "contract_status_price", keys: doc! { "nft_contract_id": 1, "status": 1, "price_sort": 1 }, unique: false },
+                Step::CreateIndex { collection: "listings", name: "contract_status_listed_at", keys: doc! { "nft_contract_id": 1, "status": 1, "listed_at": -1 }, unique: false },
+                Step::CreateIndex { collection: "sales", name: "seller_height", keys: doc! { "seller_id": 1, "block_height": -1 }, unique: false },
+                Step::CreateIndex { collection: "sales", name: "buyer_height", keys: doc! { "buyer_id": 1, "block_height": -1 }, unique: false },
+            ],
+        },
+        Migration {
+            version: 5,
+            description: "webhook delivery history index",
+            steps: vec![
+                Step::CreateIndex { collection: "webhook_deliveries", name: "subscription_delivered_at", keys: doc! { "subscription_id": 1, "delivered_at": -1 }, unique: false },
+            ],
+        },
+        Migration {
+            version: 6,
+            description: "background job status index",
+            steps: vec![
+                Step::CreateIndex { collection: "jobs", name: "kind_status", keys: doc! { "kind": 1, "status": 1 }, unique: false },
+            ],
+        },
+        Migration {
+            version: 7,
+            description: "projection history indexes for idempotent replays",
+            steps: vec![
+                Step::CreateIndex { collection: "listings", name: "history_event_id", keys: doc! { "history.event_id": 1 }, unique: false },
+                Step::CreateIndex { collection: "offers", name: "history_event_id", keys: doc! { "history.event_id": 1 }, unique: false },
+                Step::CreateIndex { collection: "bids", name: "history_event_id", keys: doc! { "history.event_id": 1 }, unique: false },
+            ],
+        },
+        Migration {
+            version: 8,
+            description: "expire webhook deliveries after 7 days",
+            steps: vec![
+                Step::CreateTtlIndex { collection: "webhook_deliveries", name: "delivered_at_ttl", field: "delivered_at", expire_after_secs: 7 * 24 * 60 * 60 },
+            ],
+        },
+    ]
+}
+
+/// Schema version this build of the capacitor expects.
+pub fn latest_version() -> i64 {
+    migrations().iter().map(|migration| migration.version).max().unwrap_or(0)
+}
+
+/// Highest schema version recorded in the database, 0 for a fresh database.
+pub async fn current_version(database: &Database) -> Result<i64, mongodb::error::Error> {
+    let applied: Collection<Document> = database.collection(MIGRATIONS_COLLECTION);
+    let options = FindOneOptions::builder().sort(doc! { "_id": -1 }).build();
+
+    let latest = applied.find_one(None, options).await?;
+    Ok(latest.and_then(|document| document.get_i64("_id").ok()).unwrap_or(0))
+}
+
+/// Applies every pending migration in order. Refuses to run against a schema
+/// written by a newer capacitor, which this build could corrupt.
+pub async fn run(database: &Database) -> Result<(), String> {
+    let current = current_version(database).await.map_err(|err| err.to_string())?;
+    let latest = latest_version();
+
+    if current > latest {
+        return Err(format!(
+            "Database schema version {} is newer than the {} supported by this build, refusing to start",
+            current, latest
+        ));
+    }
+
+    let applied: Collection<Document> = database.collection(MIGRATIONS_COLLECTION);
+
+    for migration in migrations().into_iter().filter(|migration| migration.version > current) {
+        info!(version = migration.version, "Applying schema migration: {}", migration.description);
+
+        for step in &migration.steps {
+            apply_step(database, step).await.map_err(|err| format!("Migration {} failed: {}", migration.version, err))?;
+        }
+
+        applied.insert_one(doc! {
+            "_id": migration.version,
+            "description": migration.description,
+            "applied_at": chrono::Utc::now(),
+        }, None).await.map_err(|err| err.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Recreates the indexes of collections that were dropped, e.g. while rebuilding projections.
+pub async fn create_indexes_for(database: &Database, collections: &[&str]) -> Result<(), mongodb::error::Error> {
+    for migration in migrations() {
+        for step in &migration.steps {
+            if let Step::CreateIndex { collection, .. } = step {
+                if collections.contains(collection) {
+                    apply_step(database, step).await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn apply_step(database: &Database, step: &Step) -> Result<(), mongodb::error::Error> {
+    match step {
+        Step::DropDuplicates { collection, key } => {
+            let target: Collection<Document> = database.collection(collection);
+            let pipeline = vec![
+                doc! { "$sort": { "_id": 1 } },
+                doc! { "$group": { "_id": format!("${}", key), "ids": { "$push": "$_id" }, "count": { "$sum": 1 } } },
+                doc! { "$match": { "count": { "$gt": 1 } } },
+            ];
+            let mut cursor = target.aggregate(pipeline, None).await?;
+
+            while let Some(group) = cursor.next().await {
+                let group = group?;
+                let duplicate_ids: Vec<Bson> = match group.get_array("ids") {
+                    Ok(ids) => ids.iter().skip(1).cloned().collect(),
+                    Err(_) => continue,
+                };
+
+                target.delete_many(doc! { "_id": { "$in": duplicate_ids } }, None).await?;
+            }
+        }
+        Step::CreateIndex { collection, name, keys, unique } => {
+            database.run_command(doc! {
+                "createIndexes": *collection,
+                "indexes": [{ "key": keys.clone(), "name": *name, "unique": *unique }],
+            }, None).await?;
+        }
+        Step::CreateTtlIndex { collection, name, field, expire_after_secs } => {
+            let mut keys = Document::new();
+            keys.insert(*field, 1);
+
+            database.run_command(doc! {
+                "createIndexes": *collection,
+                "indexes": [{ "key": keys, "name": *name, "expireAfterSeconds": *expire_after_secs }],
+            }, None).await?;
+        }
+    }
+
+    Ok(())
+}
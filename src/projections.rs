@@ -0,0 +1,449 @@
+use std::convert::TryFrom;
+use mongodb::{ Client, ClientSession, Database, Collection, options::{ ReplaceOptions, UpdateOptions } };
+use bson::{ Bson, doc, document::Document };
+use tokio_stream::StreamExt;
+use tracing::warn;
+
+use crate::events::{ EventPayload, IndexedEvent };
+use crate::event_log::EventLog;
+use crate::migrations;
+
+/// Collections that only hold derived state and can be dropped and rebuilt at any time.
+const PROJECTION_COLLECTIONS: [&str; 5] = ["tokens", "listings", "offers", "bids", "sales"];
+
+/// Current-state views derived from the event log: token ownership in `tokens`,
+/// the order book in `listings`, `offers` and `bids`, and completed `sales`.
+#[derive(Clone)]
+pub struct Projections {
+    client: Client,
+    database: Database,
+}
+
+impl Projections {
+    pub fn new(client: Client, database: Database) -> Self {
+        Self { client, database }
+    }
+
+    /// Applies all events of one block in a single transaction, so a crash never
+    /// leaves a block half applied. Every block, with or without events, expires the
+    /// listings that ended before it.
+    pub async fn apply_block(&self, block_height: u64, block_timestamp: u64, events: &[IndexedEvent]) -> Result<(), mongodb::error::Error> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        if let Err(err) = self.apply_events(&mut session, block_height, block_timestamp, events).await {
+            session.abort_transaction().await?;
+            return Err(err);
+        }
+
+        session.commit_transaction().await
+    }
+
+    async fn apply_events(&self, session: &mut ClientSession, block_height: u64, block_timestamp: u64, events: &[IndexedEvent]) -> Result<(), mongodb::error::Error> {
+        for event in events {
+            self.apply_ownership(session, event).await?;
+            self.apply_market(session, event).await?;
+            self.apply_sales(session, event).await?;
+        }
+
+        self.expire_listings(session, block_height, block_timestamp).await
+    }
+
+    async fn apply_ownership(&self, session: &mut ClientSession, event: &IndexedEvent) -> Result<(), mongodb::error::Error> {
+        let tokens: Collection<Document> = self.database.collection("tokens");
+        let upsert = UpdateOptions::builder().upsert(true).build();
+        let height = event.block_height as i64;
+
+        match &event.payload {
+            EventPayload::NftMint(minted) => {
+                for token_id in &minted.token_ids {
+                    tokens.update_one_with_session(
+                        doc! { "contract_id": minted.contract_id.clone(), "token_id": token_id.clone() },
+                        doc! {
+                            "$set": { "owner_id": minted.owner_id.clone(), "minted_at": height, "burned": false },
+                            "$setOnInsert": { "last_transfer": Bson::Null },
+                        },
+                        upsert.clone(),
+                        session,
+                    ).await?;
+                }
+            }
+            EventPayload::NftTransfer(transferred) => {
+                for token_id in &transferred.token_ids {
+                    tokens.update_one_with_session(
+                        doc! { "contract_id": transferred.contract_id.clone(), "token_id": token_id.clone() },
+                        doc! {
+                            "$set": { "owner_id": transferred.new_owner_id.clone(), "last_transfer": height },
+                            "$setOnInsert": { "minted_at": Bson::Null, "burned": false },
+                        },
+                        upsert.clone(),
+                        session,
+                    ).await?;
+                }
+            }
+            EventPayload::NftBurn(burned) => {
+                for token_id in &burned.token_ids {
+                    tokens.update_one_with_session(
+                        doc! { "contract_id": burned.contract_id.clone(), "token_id": token_id.clone() },
+                        doc! {
+                            "$set": { "owner_id": burned.owner_id.clone(), "burned": true },
+                            "$setOnInsert": { "minted_at": Bson::Null, "last_transfer": Bson::Null },
+                        },
+                        upsert.clone(),
+                        session,
+                    ).await?;
+                }
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Moves listings, offers and bids through their lifecycle
+    /// (active → updated → sold/cancelled/expired), keeping every step in `history`.
+    async fn apply_market(&self, session: &mut ClientSession, event: &IndexedEvent) -> Result<(), mongodb::error::Error> {
+        let listings: Collection<Document> = self.database.collection("listings");
+        let offers: Collection<Document> = self.database.collection("offers");
+        let bids: Collection<Document> = self.database.collection("bids");
+        let height = event.block_height as i64;
+
+        match &event.payload {
+            EventPayload::AddMarketData(listing) => {
+                let token_filter = open_filter(doc! { "nft_contract_id": listing.nft_contract_id.clone(), "token_id": listing.token_id.clone() });
+                close_open(&listings, session, token_filter, "cancelled", event).await?;
+
+                insert_once(&listings, session, event, doc! {
+                    "market_contract_id": event.contract_id.clone(),
+                    "nft_contract_id": listing.nft_contract_id.clone(),
+                    "token_id": listing.token_id.clone(),
+                    "owner_id": listing.owner_id.clone(),
+                    "approval_id": listing.approval_id as i64,
+                    "ft_token_id": listing.ft_token_id.clone(),
+                    "price": listing.price.clone(),
+                    "price_sort": price_sort_key(&listing.price),
+                    "started_at": listing.started_at.clone(),
+                    "ended_at": listing.ended_at.clone(),
+                    "expires_at": saturating_i64(listing.ended_at.parse::<u64>().unwrap_or(0)),
+                    "is_auction": listing.is_auction,
+                    "status": "active",
+                    "listed_at": height,
+                    "updated_at": height,
+                    "closed_at": Bson::Null,
+                    "buyer_id": Bson::Null,
+                    "history": [history_entry("active", event, Some(&listing.price))],
+                }).await?;
+            }
+            EventPayload::UpdateMarketData(update) => {
+                listings.update_many_with_session(
+                    unapplied(open_filter(doc! { "nft_contract_id": update.nft_contract_id.clone(), "token_id": update.token_id.clone() }), event),
+                    doc! {
+                        "$set": {
+                            "status": "updated",
+                            "ft_token_id": update.ft_token_id.clone(),
+                            "price": update.price.clone(),
+                            "price_sort": price_sort_key(&update.price),
+                            "updated_at": height,
+                        },
+                        "$push": { "history": history_entry("updated", event, Some(&update.price)) },
+                    },
+                    None,
+                    session,
+                ).await?;
+            }
+            EventPayload::DeleteMarketData(unlisting) => {
+                let token_filter = doc! { "nft_contract_id": unlisting.nft_contract_id.clone(), "token_id": unlisting.token_id.clone() };
+                close_open(&listings, session, open_filter(token_filter.clone()), "cancelled", event).await?;
+                close_open(&bids, session, open_filter(token_filter), "cancelled", event).await?;
+            }
+            EventPayload::AddBid(bid) => {
+                // A replayed bid may have updated an existing record instead of opening
+                // one, only the history tells them apart
+                if already_applied(&bids, session, event).await? {
+                    return Ok(());
+                }
+
+                let token_filter = doc! { "nft_contract_id": bid.nft_contract_id.clone(), "token_id": bid.token_id.clone() };
+                let listing = listings.find_one_with_session(open_filter(token_filter.clone()), None, session).await?;
+                let listing_id = listing.as_ref().and_then(|listing| listing.get_str("_id").ok()).map(str::to_string);
+
+                // A new bid supersedes every other bid still open on the auction
+                let mut outbid_filter = open_filter(token_filter.clone());
+                outbid_filter.insert("bidder_id", doc! { "$ne": bid.bidder_id.clone() });
+                close_open(&bids, session, outbid_filter, "expired", event).await?;
+
+                let mut own_filter = open_filter(token_filter);
+                own_filter.insert("bidder_id", bid.bidder_id.clone());
+                let updated = bids.update_one_with_session(
+                    unapplied(own_filter, event),
+                    doc! {
+                        "$set": {
+                            "status": "updated",
+                            "ft_token_id": bid.ft_token_id.clone(),
+                            "price": bid.price.clone(),
+                            "price_sort": price_sort_key(&bid.price),
+                            "updated_at": height,
+                        },
+                        "$push": { "history": history_entry("updated", event, Some(&bid.price)) },
+                    },
+                    None,
+                    session,
+                ).await?;
+
+                if updated.matched_count == 0 {
+                    insert_once(&bids, session, event, doc! {
+                        "market_contract_id": event.contract_id.clone(),
+                        "listing_id": listing_id,
+                        "nft_contract_id": bid.nft_contract_id.clone(),
+                        "token_id": bid.token_id.clone(),
+                        "bidder_id": bid.bidder_id.clone(),
+                        "ft_token_id": bid.ft_token_id.clone(),
+                        "price": bid.price.clone(),
+                        "price_sort": price_sort_key(&bid.price),
+                        "status": "active",
+                        "created_at": height,
+                        "updated_at": height,
+                        "closed_at": Bson::Null,
+                        "history": [history_entry("active", event, Some(&bid.price))],
+                    }).await?;
+                }
+            }
+            EventPayload::AddOffer(offer) => {
+                if already_applied(&offers, session, event).await? {
+                    return Ok(());
+                }
+
+                let own_filter = open_filter(doc! {
+                    "nft_contract_id": offer.nft_contract_id.clone(),
+                    "token_id": offer.token_id.clone(),
+                    "buyer_id": offer.buyer_id.clone(),
+                });
+                let updated = offers.update_one_with_session(
+                    unapplied(own_filter, event),
+                    doc! {
+                        "$set": {
+                            "status": "updated",
+                            "ft_token_id": offer.ft_token_id.clone(),
+                            "price": offer.price.clone(),
+                            "price_sort": price_sort_key(&offer.price),
+                            "updated_at": height,
+                        },
+                        "$push": { "history": history_entry("updated", event, Some(&offer.price)) },
+                    },
+                    None,
+                    session,
+                ).await?;
+
+                if updated.matched_count == 0 {
+                    insert_once(&offers, session, event, doc! {
+                        "market_contract_id": event.contract_id.clone(),
+                        "nft_contract_id": offer.nft_contract_id.clone(),
+                        "token_id": offer.token_id.clone(),
+                        "buyer_id": offer.buyer_id.clone(),
+                        "ft_token_id": offer.ft_token_id.clone(),
+                        "price": offer.price.clone(),
+                        "price_sort": price_sort_key(&offer.price),
+                        "status": "active",
+                        "created_at": height,
+                        "updated_at": height,
+                        "closed_at": Bson::Null,
+                        "history": [history_entry("active", event, Some(&offer.price))],
+                    }).await?;
+                }
+            }
+            EventPayload::DeleteOffer(unoffer) => {
+                let own_filter = open_filter(doc! {
+                    "nft_contract_id": unoffer.nft_contract_id.clone(),
+                    "token_id": unoffer.token_id.clone(),
+                    "buyer_id": unoffer.buyer_id.clone(),
+                });
+                close_open(&offers, session, own_filter, "cancelled", event).await?;
+            }
+            EventPayload::ResolvePurchase(purchase) => {
+                let token_filter = doc! { "nft_contract_id": purchase.nft_contract_id.clone(), "token_id": purchase.token_id.clone() };
+                listings.update_many_with_session(
+                    unapplied(open_filter(token_filter.clone()), event),
+                    doc! {
+                        "$set": { "status": "sold", "buyer_id": purchase.buyer_id.clone(), "closed_at": height, "updated_at": height },
+                        "$push": { "history": history_entry("sold", event, Some(&purchase.price)) },
+                    },
+                    None,
+                    session,
+                ).await?;
+
+                let mut winner_filter = open_filter(token_filter.clone());
+                if purchase.is_offer {
+                    winner_filter.insert("buyer_id", purchase.buyer_id.clone());
+                    close_open(&offers, session, winner_filter, "sold", event).await?;
+                } else {
+                    winner_filter.insert("bidder_id", purchase.buyer_id.clone());
+                    close_open(&bids, session, winner_filter, "sold", event).await?;
+                }
+
+                close_open(&bids, session, open_filter(token_filter), "expired", event).await?;
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Fixed price listings past their `ended_at` can no longer be bought. Auctions stay
+    /// open after ending until they are resolved or cancelled.
+    async fn expire_listings(&self, session: &mut ClientSession, block_height: u64, block_timestamp: u64) -> Result<(), mongodb::error::Error> {
+        let listings: Collection<Document> = self.database.collection("listings");
+        let height = saturating_i64(block_height);
+        let filter = open_filter(doc! {
+            "is_auction": false,
+            "expires_at": { "$gt": 0i64, "$lt": saturating_i64(block_timestamp) },
+        });
+
+        listings.update_many_with_session(
+            filter,
+            doc! {
+                "$set": { "status": "expired", "closed_at": height, "updated_at": height },
+                "$push": { "history": { "status": "expired", "block_height": height, "event_id": Bson::Null, "price": Bson::Null } },
+            },
+            None,
+            session,
+        ).await?;
+
+        Ok(())
+    }
+
+    async fn apply_sales(&self, session: &mut ClientSession, event: &IndexedEvent) -> Result<(), mongodb::error::Error> {
+        let sales: Collection<Document> = self.database.collection("sales");
+
+        if let EventPayload::ResolvePurchase(purchase) = &event.payload {
+            let options = ReplaceOptions::builder().upsert(true).build();
+
+            sales.replace_one_with_session(
+                event.id_filter(),
+                doc! {
+                    "market_contract_id": event.contract_id.clone(),
+                    "nft_contract_id": purchase.nft_contract_id.clone(),
+                    "token_id": purchase.token_id.clone(),
+                    "seller_id": purchase.owner_id.clone(),
+                    "buyer_id": purchase.buyer_id.clone(),
+                    "ft_token_id": purchase.ft_token_id.clone(),
+                    "price": purchase.price.clone(),
+                    "price_sort": price_sort_key(&purchase.price),
+                    "is_offer": purchase.is_offer,
+                    "block_height": event.block_height as i64,
+                    "block_timestamp": event.block_timestamp as i64,
+                },
+                options,
+                session,
+            ).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops every projection and replays the event log into them, one transaction
+    /// per block. Returns the number of events replayed.
+    pub async fn rebuild(&self, event_log: &EventLog) -> Result<usize, mongodb::error::Error> {
+        for collection_name in PROJECTION_COLLECTIONS.iter() {
+            let collection: Collection<Document> = self.database.collection(collection_name);
+            collection.drop(None).await?;
+        }
+        migrations::create_indexes_for(&self.database, &PROJECTION_COLLECTIONS).await?;
+
+        let mut cursor = event_log.read_from(0).await?;
+        let mut block_events: Vec<IndexedEvent> = vec![];
+        let mut replayed = 0;
+
+        while let Some(document) = cursor.next().await {
+            let event = match IndexedEvent::from_document(document?) {
+                Some(event) => event,
+                None => {
+                    warn!("Skipping unreadable event in the event log");
+                    continue;
+                }
+            };
+
+            if block_events.first().map_or(false, |first| first.block_height != event.block_height) {
+                replayed += self.replay_block(&block_events).await?;
+                block_events.clear();
+            }
+
+            block_events.push(event);
+        }
+
+        replayed += self.replay_block(&block_events).await?;
+
+        Ok(replayed)
+    }
+
+    async fn replay_block(&self, block_events: &[IndexedEvent]) -> Result<usize, mongodb::error::Error> {
+        match block_events.first() {
+            Some(first) => {
+                self.apply_block(first.block_height, first.block_timestamp, block_events).await?;
+                Ok(block_events.len())
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+/// Restricts a filter to listings, offers or bids that are still open.
+pub(crate) fn open_filter(mut filter: Document) -> Document {
+    filter.insert("status", doc! { "$in": ["active", "updated"] });
+    filter
+}
+
+/// Restricts a filter to records `event` has not changed yet, so replaying a block after
+/// a restart never records the same step in `history` twice.
+fn unapplied(mut filter: Document, event: &IndexedEvent) -> Document {
+    filter.insert("history.event_id", doc! { "$ne": event.event_id.clone() });
+    filter
+}
+
+async fn already_applied(collection: &Collection<Document>, session: &mut ClientSession, event: &IndexedEvent) -> Result<bool, mongodb::error::Error> {
+    let applied = collection.find_one_with_session(doc! { "history.event_id": event.event_id.clone() }, None, session).await?;
+    Ok(applied.is_some())
+}
+
+/// Inserts the listing, offer or bid `event` opens, keyed by its event id. A replayed
+/// block finds it already there and leaves it as it is.
+async fn insert_once(collection: &Collection<Document>, session: &mut ClientSession, event: &IndexedEvent, record: Document) -> Result<(), mongodb::error::Error> {
+    let upsert = UpdateOptions::builder().upsert(true).build();
+    collection.update_one_with_session(event.id_filter(), doc! { "$setOnInsert": record }, upsert, session).await?;
+
+    Ok(())
+}
+
+async fn close_open(collection: &Collection<Document>, session: &mut ClientSession, filter: Document, status: &str, event: &IndexedEvent) -> Result<(), mongodb::error::Error> {
+    let height = event.block_height as i64;
+
+    collection.update_many_with_session(
+        unapplied(filter, event),
+        doc! {
+            "$set": { "status": status, "closed_at": height, "updated_at": height },
+            "$push": { "history": history_entry(status, event, None) },
+        },
+        None,
+        session,
+    ).await?;
+
+    Ok(())
+}
+
+fn history_entry(status: &str, event: &IndexedEvent, price: Option<&String>) -> Document {
+    doc! {
+        "status": status,
+        "block_height": event.block_height as i64,
+        "event_id": event.event_id.clone(),
+        "price": price.cloned(),
+    }
+}
+
+/// MongoDB has no unsigned integers; values past `i64::MAX` are stored as `i64::MAX`.
+fn saturating_i64(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+/// Prices are yoctoNEAR strings; zero padding them makes string order match numeric order.
+pub fn price_sort_key(price: &str) -> String {
+    format!("{:0>40}", price)
+}
@@ -0,0 +1,267 @@
+use actix_web::{ web, HttpRequest, HttpResponse, http::StatusCode };
+use bson::{ Bson, doc, document::Document };
+use mongodb::{ Collection, options::FindOptions };
+use qstring::{ QString };
+use serde::Serialize;
+use serde_json::Value;
+use tokio_stream::StreamExt;
+
+use crate::admin_api::api_error;
+use crate::auth::{ RequireScope, Scope };
+use crate::http_server::AppState;
+use crate::projections;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+/// Every query endpoint answers with one page of records and the cursor to pass as
+/// `cursor` for the next one, `null` on the last page.
+#[derive(Serialize)]
+struct Page {
+    data: Vec<Value>,
+    next_cursor: Option<String>,
+}
+
+/// Collection, filter and ordering of a paginated query. Records with the same sort
+/// value are ordered by `_id`, so the cursor is the sort value and `_id` of the last record.
+struct PageQuery {
+    collection: &'static str,
+    filter: Document,
+    sort_field: &'static str,
+    descending: bool,
+}
+
+fn encode_cursor(document: &Document, sort_field: &str) -> String {
+    let position = Bson::Array(vec![
+        document.get(sort_field).cloned().unwrap_or(Bson::Null),
+        document.get("_id").cloned().unwrap_or(Bson::Null),
+    ]);
+
+    hex::encode(position.into_relaxed_extjson().to_string())
+}
+
+fn decode_cursor(cursor: &str) -> Result<(Bson, Bson), String> {
+    let json = hex::decode(cursor).ok().and_then(|bytes| String::from_utf8(bytes).ok()).ok_or("Malformed cursor")?;
+    let position: Value = serde_json::from_str(&json).map_err(|_| "Malformed cursor")?;
+
+    match Bson::from(position) {
+        Bson::Array(mut values) if values.len() == 2 => {
+            let id = values.pop().unwrap();
+            Ok((values.pop().unwrap(), id))
+        }
+        _ => Err("Malformed cursor".to_string()),
+    }
+}
+
+/// Records are returned without their MongoDB `_id`, which is only meaningful as part of the cursor.
+fn to_json(mut document: Document) -> Value {
+    document.remove("_id");
+    Bson::Document(document).into_relaxed_extjson()
+}
+
+async fn fetch_page(data: &AppState, req: &HttpRequest, query: PageQuery) -> HttpResponse {
+    let query_string = QString::from(req.query_string());
+    let limit = match query_string.get("limit").map(str::parse::<i64>) {
+        None => DEFAULT_PAGE_SIZE,
+        Some(Ok(limit)) if limit > 0 => limit.min(MAX_PAGE_SIZE),
+        Some(_) => return api_error(StatusCode::BAD_REQUEST, "invalid_limit", "`limit` must be a positive number"),
+    };
+
+    let mut filter = query.filter;
+    if let Some(cursor) = query_string.get("cursor") {
+        let (sort_value, id) = match decode_cursor(cursor) {
+            Ok(position) => position,
+            Err(err) => return api_error(StatusCode::BAD_REQUEST, "invalid_cursor", err),
+        };
+        let after = if query.descending { "$lt" } else { "$gt" };
+
+        let past_cursor = doc! {
+            "$or": [
+                { query.sort_field: { after: sort_value.clone() } },
+                { query.sort_field: sort_value, "_id": { after: id } },
+            ]
+        };
+        filter = doc! { "$and": [filter, past_cursor] };
+    }
+
+    let direction = if query.descending { -1 } else { 1 };
+    let options = FindOptions::builder()
+        .sort(doc! { query.sort_field: direction, "_id": direction })
+        .limit(limit + 1)
+        .build();
+    let collection: Collection<Document> = data.database.collection(query.collection);
+
+    let mut cursor = match collection.find(filter, options).await {
+        Ok(cursor) => cursor,
+        Err(err) => return api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+    };
+    let mut documents = vec![];
+    while let Some(document) = cursor.next().await {
+        match document {
+            Ok(document) => documents.push(document),
+            Err(err) => return api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err),
+        }
+    }
+
+    // One record more than the page size was fetched to tell whether another page follows
+    let next_cursor = match documents.len() as i64 > limit {
+        true => {
+            documents.truncate(limit as usize);
+            documents.last().map(|document| encode_cursor(document, query.sort_field))
+        }
+        false => None,
+    };
+
+    HttpResponse::Ok().json(Page {
+        data: documents.into_iter().map(to_json).collect(),
+        next_cursor,
+    })
+}
+
+/// Open offers or bids unless `status` names another one, e.g. `sold`, or is `all`.
+fn status_filter(req: &HttpRequest, mut filter: Document) -> Document {
+    match QString::from(req.query_string()).get("status") {
+        None => projections::open_filter(filter),
+        Some("all") => filter,
+        Some(status) => {
+            filter.insert("status", status);
+            filter
+        }
+    }
+}
+
+async fn tokens_by_owner(data: web::Data<AppState>, req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    fetch_page(&data, &req, PageQuery {
+        collection: "tokens",
+        filter: doc! { "owner_id": path.into_inner(), "burned": false },
+        sort_field: "token_id",
+        descending: false,
+    }).await
+}
+
+async fn sales_by_account(data: web::Data<AppState>, req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    let account_id = path.into_inner();
+    let filter = match QString::from(req.query_string()).get("role") {
+        Some("seller") => doc! { "seller_id": account_id },
+        Some("buyer") => doc! { "buyer_id": account_id },
+        None => doc! { "$or": [{ "seller_id": account_id.clone() }, { "buyer_id": account_id }] },
+        Some(_) => return api_error(StatusCode::BAD_REQUEST, "invalid_role", "`role` must be seller or buyer"),
+    };
+
+    fetch_page(&data, &req, PageQuery { collection: "sales", filter, sort_field: "block_height", descending: true }).await
+}
+
+async fn tokens_by_contract(data: web::Data<AppState>, req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    fetch_page(&data, &req, PageQuery {
+        collection: "tokens",
+        filter: doc! { "contract_id": path.into_inner(), "burned": false },
+        sort_field: "token_id",
+        descending: false,
+    }).await
+}
+
+/// Open listings of the collection, sorted by `sort`: `price_asc` (default), `price_desc` or `newest`.
+async fn listings_by_contract(data: web::Data<AppState>, req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    let (sort_field, descending) = match QString::from(req.query_string()).get("sort") {
+        None | Some("price_asc") => ("price_sort", false),
+        Some("price_desc") => ("price_sort", true),
+        Some("newest") => ("listed_at", true),
+        Some(_) => return api_error(StatusCode::BAD_REQUEST, "invalid_sort", "`sort` must be price_asc, price_desc or newest"),
+    };
+    let filter = projections::open_filter(doc! { "nft_contract_id": path.into_inner() });
+
+    fetch_page(&data, &req, PageQuery { collection: "listings", filter, sort_field, descending }).await
+}
+
+async fn token_offers(data: web::Data<AppState>, req: HttpRequest, path: web::Path<(String, String)>) -> HttpResponse {
+    let (contract_id, token_id) = path.into_inner();
+    let filter = status_filter(&req, doc! { "nft_contract_id": contract_id, "token_id": token_id });
+
+    fetch_page(&data, &req, PageQuery { collection: "offers", filter, sort_field: "price_sort", descending: true }).await
+}
+
+async fn token_bids(data: web::Data<AppState>, req: HttpRequest, path: web::Path<(String, String)>) -> HttpResponse {
+    let (contract_id, token_id) = path.into_inner();
+    let filter = status_filter(&req, doc! { "nft_contract_id": contract_id, "token_id": token_id });
+
+    fetch_page(&data, &req, PageQuery { collection: "bids", filter, sort_field: "price_sort", descending: true }).await
+}
+
+async fn token_sales(data: web::Data<AppState>, req: HttpRequest, path: web::Path<(String, String)>) -> HttpResponse {
+    let (contract_id, token_id) = path.into_inner();
+
+    fetch_page(&data, &req, PageQuery {
+        collection: "sales",
+        filter: doc! { "nft_contract_id": contract_id, "token_id": token_id },
+        sort_field: "block_height",
+        descending: true,
+    }).await
+}
+
+fn accounts() -> actix_web::Scope {
+    web::scope("/accounts")
+        .route("/{account_id}/tokens", web::get().to(tokens_by_owner))
+        .route("/{account_id}/sales", web::get().to(sales_by_account))
+}
+
+fn collections() -> actix_web::Scope {
+    web::scope("/collections")
+        .route("/{contract_id}/tokens", web::get().to(tokens_by_contract))
+        .route("/{contract_id}/listings", web::get().to(listings_by_contract))
+        .route("/{contract_id}/tokens/{token_id}/offers", web::get().to(token_offers))
+        .route("/{contract_id}/tokens/{token_id}/bids", web::get().to(token_bids))
+        .route("/{contract_id}/tokens/{token_id}/sales", web::get().to(token_sales))
+}
+
+/// Query routes for the public listener, served without a token.
+pub fn configure_public(cfg: &mut web::ServiceConfig) {
+    cfg.service(accounts()).service(collections());
+}
+
+/// Query routes for the admin listener, requiring the `read` scope.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(accounts().wrap(RequireScope(Scope::Read)))
+        .service(collections().wrap(RequireScope(Scope::Read)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        let document = doc! { "_id": "nft.near:1", "price_sort": 1500i64 };
+
+        let (sort_value, id) = decode_cursor(&encode_cursor(&document, "price_sort")).unwrap();
+
+        assert_eq!(sort_value, Bson::Int64(1500));
+        assert_eq!(id, Bson::String("nft.near:1".to_string()));
+    }
+
+    #[test]
+    fn cursor_of_a_missing_sort_field_is_null() {
+        let document = doc! { "_id": "nft.near:1" };
+
+        let (sort_value, _) = decode_cursor(&encode_cursor(&document, "listed_at")).unwrap();
+
+        assert_eq!(sort_value, Bson::Null);
+    }
+
+    #[test]
+    fn decode_cursor_rejects_bad_cursors() {
+        let cases = vec![
+            "".to_string(),
+            "not hex".to_string(),
+            "abc".to_string(),
+            hex::encode([0xff, 0xfe]),
+            hex::encode("not json"),
+            hex::encode("{\"a\": 1}"),
+            hex::encode("[1]"),
+            hex::encode("[1, 2, 3]"),
+        ];
+
+        for cursor in cases {
+            assert!(decode_cursor(&cursor).is_err(), "'{}' should not decode", cursor);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+use std::sync::{ Arc, Mutex };
+use mongodb::Database;
+use serde::Serialize;
+use tokio::signal::unix::{ signal, SignalKind };
+use tracing::{ error, info, warn };
+
+use crate::Capacitor;
+use crate::configs::{ Config, Opts };
+use crate::sinks::{ self, SinkRouter };
+
+#[derive(Debug)]
+pub enum ReloadError {
+    /// The config no longer loads or validates
+    Invalid(String),
+    /// The config changes sections that only take effect on a restart
+    Rejected(Vec<&'static str>),
+    /// The config is valid but could not be applied
+    Failed(String),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReloadError::Invalid(err) => write!(f, "{}", err),
+            ReloadError::Rejected(sections) => write!(f, "Changing [{}] requires a restart, nothing was reloaded", sections.join("], [")),
+            ReloadError::Failed(err) => write!(f, "Failed to apply the config: {}", err),
+        }
+    }
+}
+
+/// Times the stored contracts are read again when the watch list changed while they
+/// were being read.
+const WATCHED_READ_ATTEMPTS: u32 = 3;
+
+#[derive(Serialize, Debug)]
+pub struct ReloadSummary {
+    pub sinks: Vec<String>,
+    pub contracts: usize,
+}
+
+/// Re-reads the config file and the stored contracts, then swaps the sinks and the watch
+/// list together while holding the capacitor. A block matches its contracts against one
+/// watch list and is queued on one set of sinks, but a block in flight during a reload may
+/// have been matched against the old list and still be queued on the new sinks.
+#[derive(Clone)]
+pub struct Reloader {
+    opts: Opts,
+    /// The config applied last; holding it also keeps two reloads from interleaving
+    current: Arc<tokio::sync::Mutex<Config>>,
+    capacitor_ins: Arc<Mutex<Capacitor>>,
+    sink_router: SinkRouter,
+    database: Database,
+}
+
+impl Reloader {
+    pub(crate) fn new(opts: Opts, config: Config, capacitor_ins: Arc<Mutex<Capacitor>>, sink_router: SinkRouter, database: Database) -> Self {
+        Self { opts, current: Arc::new(tokio::sync::Mutex::new(config)), capacitor_ins, sink_router, database }
+    }
+
+    pub async fn reload(&self) -> Result<ReloadSummary, ReloadError> {
+        let result = self.apply().await;
+
+        match &result {
+            Ok(summary) => info!(sinks = ?summary.sinks, contracts = summary.contracts, "Reloaded the config"),
+            Err(err) => warn!("Config reload failed: {}", err),
+        }
+        result
+    }
+
+    async fn apply(&self) -> Result<ReloadSummary, ReloadError> {
+        let mut current = self.current.lock().await;
+
+        let config = Config::load(&self.opts).map_err(ReloadError::Invalid)?;
+        let fixed_changes = current.fixed_changes(&config);
+        if !fixed_changes.is_empty() {
+            return Err(ReloadError::Rejected(fixed_changes));
+        }
+
+        let sink_configs = sinks::load_sink_configs(&config).map_err(ReloadError::Invalid)?;
+        let sink_names = sink_configs.iter().map(|sink| sink.name.clone()).collect();
+
+        // The stored contracts are read without holding the capacitor, so a contract written
+        // through the admin API meanwhile would be lost; the read is repeated when that happened.
+        let mut attempt = 1;
+        let contracts = loop {
+            let watch_changes = self.capacitor_ins.lock().unwrap().watch_changes();
+            let watched = Capacitor::read_watched(&self.database, config.contracts.clone()).await.map_err(ReloadError::Failed)?;
+
+            let mut capacitor_ins = self.capacitor_ins.lock().unwrap();
+            if capacitor_ins.watch_changes() != watch_changes {
+                if attempt == WATCHED_READ_ATTEMPTS {
+                    return Err(ReloadError::Failed("The watched contracts kept changing while they were read".to_string()));
+                }
+                attempt += 1;
+                continue;
+            }
+
+            self.sink_router.replace_sinks(sink_configs).map_err(ReloadError::Failed)?;
+            capacitor_ins.replace_watched(watched);
+            break capacitor_ins.contracts().len();
+        };
+        *current = config;
+
+        Ok(ReloadSummary { sinks: sink_names, contracts })
+    }
+
+    /// Reloads on every SIGHUP for as long as the capacitor runs.
+    pub async fn reload_on_sighup(self) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => return error!("Could not listen for SIGHUP, reload through the admin API instead: {}", err),
+        };
+
+        while hangups.recv().await.is_some() {
+            info!("Received SIGHUP, reloading the config");
+            // The outcome is logged by `reload`
+            let _ = self.reload().await;
+        }
+    }
+}
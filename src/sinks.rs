@@ -0,0 +1,555 @@
+use std::fs::{ self, File, OpenOptions };
+use std::io::Write;
+use std::str::FromStr;
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex, RwLock };
+use std::sync::atomic::{ AtomicI64, AtomicUsize, Ordering };
+use std::time::Duration;
+use async_trait::async_trait;
+use chrono::{ DateTime, TimeZone, Utc };
+use tokio::sync::mpsc;
+use mongodb::{ Database, Collection, options::FindOptions };
+use bson::{ doc, document::Document };
+use reqwest::StatusCode;
+use serde::{ Serialize, Deserialize };
+use tokio_stream::StreamExt;
+use tracing::{ debug, error, info, warn };
+
+use crate::configs::Config;
+use crate::database;
+use crate::metrics;
+use crate::events::IndexedEvent;
+
+/// Number of events a single sink may have queued before dispatching blocks.
+const SINK_QUEUE_SIZE: usize = 10_000;
+/// Number of backfilled events a single sink may have queued before the backfill waits.
+/// They are kept apart from live events, which are always delivered first.
+const BACKFILL_QUEUE_SIZE: usize = 1_000;
+
+/// Limits of a single HTTP delivery, so an unresponsive endpoint fails the attempt
+/// instead of stalling its sink's worker.
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const HTTP_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Client for HTTP sinks and webhooks, with connect and request timeouts.
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .timeout(HTTP_REQUEST_TIMEOUT)
+        .build()
+        .expect("HTTP client settings are valid")
+}
+
+/// A destination events are delivered to.
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn deliver(&self, event: &IndexedEvent) -> Result<(), String>;
+}
+
+/// POSTs each event to `<url>/<event api path>`, the way `PUBLIC_API` expects it. Events
+/// without an API path are skipped.
+pub struct HttpSink {
+    /// Sink name, the metrics label; the URL may carry secrets
+    name: String,
+    url: String,
+    signature_header: String,
+    client: reqwest::Client,
+}
+
+impl HttpSink {
+    pub fn new(name: String, url: String, signature_header: String) -> Self {
+        Self {
+            name,
+            url,
+            signature_header,
+            client: http_client(),
+        }
+    }
+}
+
+#[async_trait]
+impl EventSink for HttpSink {
+    async fn deliver(&self, event: &IndexedEvent) -> Result<(), String> {
+        let api_path = match event.payload.api_path() {
+            Some(api_path) => api_path,
+            None => {
+                debug!(event = event.payload.event_type(), event_id = %event.event_id, "Skipped event without an api path");
+                return Ok(());
+            }
+        };
+        let final_url = format!("{}/{}", self.url, api_path);
+
+        let res = self.client
+            .post(&final_url)
+            .header("Signature", self.signature_header.clone())
+            .json(&event.payload.data())
+            .send()
+            .await
+            .map_err(|err| {
+                metrics::HTTP_DELIVERY_RESPONSES.with_label_values(&[&self.name, "error"]).inc();
+                err.to_string()
+            })?;
+
+        metrics::HTTP_DELIVERY_RESPONSES.with_label_values(&[&self.name, res.status().as_str()]).inc();
+        debug!(endpoint = %final_url, status = res.status().as_u16(), event = event.payload.event_type(), event_id = %event.event_id, "Delivered event over http");
+        match res.status() {
+            StatusCode::OK => Ok(()),
+            s => Err(format!("Received response status {:?} when handling {}", s, event.payload.event_type())),
+        }
+    }
+}
+
+/// Upserts each event into its MongoDB collection, keyed by event id.
+pub struct MongoSink {
+    database: Database,
+}
+
+impl MongoSink {
+    pub fn new(database: Database) -> Self {
+        Self { database }
+    }
+}
+
+#[async_trait]
+impl EventSink for MongoSink {
+    async fn deliver(&self, event: &IndexedEvent) -> Result<(), String> {
+        database::upsert_event_in_database(&self.database, event).await.map_err(|err| err.to_string())
+    }
+}
+
+/// Appends each event as a JSON line to a local file.
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("Could not open sink file {}: {}", path, err))?;
+
+        Ok(Self { file: Mutex::new(file) })
+    }
+}
+
+#[async_trait]
+impl EventSink for FileSink {
+    async fn deliver(&self, event: &IndexedEvent) -> Result<(), String> {
+        let line = serde_json::to_string(event).map_err(|err| err.to_string())?;
+        let mut file = self.file.lock().unwrap();
+
+        writeln!(file, "{}", line).map_err(|err| err.to_string())
+    }
+}
+
+/// Prints each event as a JSON line.
+pub struct StdoutSink;
+
+#[async_trait]
+impl EventSink for StdoutSink {
+    async fn deliver(&self, event: &IndexedEvent) -> Result<(), String> {
+        let line = serde_json::to_string(event).map_err(|err| err.to_string())?;
+        println!("{}", line);
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkKind {
+    Http { url: String },
+    MongoDb,
+    File { path: String },
+    Stdout,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts before the event is moved to `dead_letters`, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff before retry number `attempt` (starting at 1)
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        let backoff_ms = self.initial_backoff_ms.saturating_mul(factor).min(self.max_backoff_ms);
+
+        Duration::from_millis(backoff_ms)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SinkConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    /// Event types delivered to this sink, all of them when omitted
+    #[serde(default)]
+    pub event_types: Option<Vec<String>>,
+    /// Contracts whose events are delivered to this sink, all of them when omitted
+    #[serde(default)]
+    pub contracts: Option<Vec<String>>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+impl SinkConfig {
+    pub fn accepts(&self, event: &IndexedEvent) -> bool {
+        let accepts_type = match &self.event_types {
+            Some(event_types) => event_types.iter().any(|event_type| event_type == event.payload.event_type()),
+            None => true,
+        };
+        let accepts_contract = match &self.contracts {
+            Some(contracts) => contracts.contains(&event.contract_id),
+            None => true,
+        };
+
+        accepts_type && accepts_contract
+    }
+}
+
+/// Legacy `DELIVERY_MODE` values, kept so existing deployments need no sinks file.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryMode {
+    Http,
+    MongoDb,
+    Both,
+}
+
+impl FromStr for DeliveryMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.to_lowercase().as_str() {
+            "http" => Ok(DeliveryMode::Http),
+            "mongodb" => Ok(DeliveryMode::MongoDb),
+            "both" => Ok(DeliveryMode::Both),
+            _ => Err(format!("Unknown delivery mode '{}', expected one of: http, mongodb, both", mode)),
+        }
+    }
+}
+
+/// The configured `[[sinks]]`, else the ones listed in `delivery.sinks_file`, else the
+/// sinks of `delivery.mode`.
+pub fn load_sink_configs(config: &Config) -> Result<Vec<SinkConfig>, String> {
+    if let Some(sinks) = &config.sinks {
+        return Ok(sinks.clone());
+    }
+    if let Some(path) = &config.delivery.sinks_file {
+        let contents = fs::read_to_string(path).map_err(|err| format!("Could not read sinks file {}: {}", path.display(), err))?;
+        return serde_json::from_str(&contents).map_err(|err| format!("Malformed sinks file {}: {}", path.display(), err));
+    }
+
+    let delivery_mode = config.delivery.mode;
+    let mut configs = vec![];
+
+    if delivery_mode != DeliveryMode::MongoDb {
+        configs.push(SinkConfig {
+            name: "public_api".to_string(),
+            kind: SinkKind::Http {
+                url: config.delivery.public_api.clone().expect("delivery.public_api is validated with the http delivery mode"),
+            },
+            event_types: None,
+            contracts: None,
+            retry: RetryPolicy::default(),
+        });
+    }
+
+    if delivery_mode != DeliveryMode::Http {
+        configs.push(SinkConfig {
+            name: "mongodb".to_string(),
+            kind: SinkKind::MongoDb,
+            event_types: None,
+            contracts: None,
+            retry: RetryPolicy::default(),
+        });
+    }
+
+    Ok(configs)
+}
+
+#[derive(Clone)]
+struct SinkRoute {
+    config: SinkConfig,
+    sender: mpsc::Sender<IndexedEvent>,
+    backfill_sender: mpsc::Sender<IndexedEvent>,
+}
+
+/// Fans every event out to all sinks whose filter accepts it. Each sink has its own
+/// queue and worker, so a slow or failing destination does not hold back the others.
+#[derive(Clone)]
+pub struct SinkRouter {
+    /// Routes of the configured sinks, swapped as a whole when the config is reloaded
+    routes: Arc<RwLock<Vec<SinkRoute>>>,
+    /// Routes of the webhook subscriptions, keyed by subscription id. They change at runtime.
+    webhooks: Arc<RwLock<HashMap<String, SinkRoute>>>,
+    outbox_depth: Arc<AtomicUsize>,
+    /// Unix milliseconds of the last successful delivery, 0 before the first one
+    last_delivery_at: Arc<AtomicI64>,
+    database: Database,
+    signature_header: String,
+    dead_letters: Collection<Document>,
+}
+
+impl SinkRouter {
+    pub fn start(configs: Vec<SinkConfig>, database: Database, signature_header: String) -> Self {
+        let router = Self {
+            routes: Arc::new(RwLock::new(vec![])),
+            webhooks: Arc::new(RwLock::new(HashMap::new())),
+            outbox_depth: Arc::new(AtomicUsize::new(0)),
+            last_delivery_at: Arc::new(AtomicI64::new(0)),
+            dead_letters: database.collection("dead_letters"),
+            database,
+            signature_header,
+        };
+        router.replace_sinks(configs).unwrap_or_else(|err| panic!("{}", err));
+
+        router
+    }
+
+    fn build_sink(&self, config: &SinkConfig) -> Result<Box<dyn EventSink>, String> {
+        Ok(match &config.kind {
+            SinkKind::Http { url } => Box::new(HttpSink::new(config.name.clone(), url.clone(), self.signature_header.clone())),
+            SinkKind::MongoDb => Box::new(MongoSink::new(self.database.clone())),
+            SinkKind::File { path } => Box::new(FileSink::open(path)?),
+            SinkKind::Stdout => Box::new(StdoutSink),
+        })
+    }
+
+    /// Swaps the configured sinks in one step, leaving them untouched when any of the new
+    /// ones cannot be opened. Sinks whose settings did not change keep their worker and queue;
+    /// replaced ones still deliver what they already queued before they stop.
+    pub fn replace_sinks(&self, configs: Vec<SinkConfig>) -> Result<(), String> {
+        let current = self.routes.read().unwrap().clone();
+        let kept = |config: &SinkConfig| current.iter().find(|route| &route.config == config).cloned();
+
+        let sinks = configs.iter()
+            .map(|config| match kept(config) {
+                Some(_) => Ok(None),
+                None => self.build_sink(config).map(Some),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let routes = configs.into_iter().zip(sinks).map(|(config, sink)| match sink {
+            Some(sink) => self.spawn_route(config, sink),
+            None => kept(&config).expect("Sinks without a new worker are kept"),
+        }).collect::<Vec<_>>();
+
+        info!(sinks = ?routes.iter().map(|route| route.config.name.as_str()).collect::<Vec<_>>(), "Delivering events");
+        *self.routes.write().unwrap() = routes;
+
+        Ok(())
+    }
+
+    fn spawn_route(&self, config: SinkConfig, sink: Box<dyn EventSink>) -> SinkRoute {
+        let (sender, receiver) = mpsc::channel(SINK_QUEUE_SIZE);
+        let (backfill_sender, backfill_receiver) = mpsc::channel(BACKFILL_QUEUE_SIZE);
+
+        actix::spawn(run_sink(config.clone(), sink, receiver, backfill_receiver, self.outbox_depth.clone(), self.last_delivery_at.clone(), self.dead_letters.clone()));
+
+        SinkRoute { config, sender, backfill_sender }
+    }
+
+    /// Starts delivering to a webhook subscription, replacing its previous route. The
+    /// previous worker still delivers what it already queued before it stops.
+    pub fn set_webhook(&self, subscription_id: &str, config: SinkConfig, sink: Box<dyn EventSink>) {
+        let route = self.spawn_route(config, sink);
+        self.webhooks.write().unwrap().insert(subscription_id.to_string(), route);
+    }
+
+    pub fn remove_webhook(&self, subscription_id: &str) {
+        self.webhooks.write().unwrap().remove(subscription_id);
+    }
+
+    /// Configured sinks followed by the current webhook subscriptions.
+    fn all_routes(&self) -> Vec<SinkRoute> {
+        let routes = self.routes.read().unwrap();
+        let webhooks = self.webhooks.read().unwrap();
+        routes.iter().cloned().chain(webhooks.values().cloned()).collect()
+    }
+
+    /// Queues the events of a whole block, each on the sinks `only_sinks` returns for it,
+    /// against one snapshot of the routes so a reload never splits a block between the
+    /// old and the new sinks.
+    pub async fn dispatch_block(&self, events: &[IndexedEvent], only_sinks: impl FnMut(&IndexedEvent) -> Option<Vec<String>>) {
+        self.dispatch_events(events, only_sinks, false).await;
+    }
+
+    /// Like `dispatch_block`, but on the sinks' backfill queues, so a backfill waits for
+    /// room in its own queue instead of filling the one live blocks wait on.
+    pub async fn dispatch_backfill_block(&self, events: &[IndexedEvent], only_sinks: impl FnMut(&IndexedEvent) -> Option<Vec<String>>) {
+        self.dispatch_events(events, only_sinks, true).await;
+    }
+
+    async fn dispatch_events(&self, events: &[IndexedEvent], mut only_sinks: impl FnMut(&IndexedEvent) -> Option<Vec<String>>, backfill: bool) {
+        let routes = self.all_routes();
+
+        for event in events {
+            self.dispatch_to(&routes, event, only_sinks(event).as_deref(), backfill).await;
+        }
+    }
+
+    /// Queues the event on every accepting sink of `routes`, or only on the named ones when
+    /// `only_sinks` is given.
+    async fn dispatch_to(&self, routes: &[SinkRoute], event: &IndexedEvent, only_sinks: Option<&[String]>, backfill: bool) {
+        let targeted = |route: &&SinkRoute| only_sinks.map_or(true, |names| names.contains(&route.config.name));
+
+        for route in routes.iter().filter(targeted).filter(|route| route.config.accepts(event)) {
+            if let Err(err) = self.enqueue(route, event.clone(), backfill).await {
+                error!(sink = %route.config.name, event_id = %event.event_id, "{}, dropping event", err);
+            }
+        }
+    }
+
+    async fn enqueue(&self, route: &SinkRoute, event: IndexedEvent, backfill: bool) -> Result<(), String> {
+        self.outbox_depth.fetch_add(1, Ordering::SeqCst);
+        let sender = match backfill {
+            true => &route.backfill_sender,
+            false => &route.sender,
+        };
+
+        match sender.send(event).await {
+            Ok(()) => Ok(()),
+            Err(_) => {
+                self.outbox_depth.fetch_sub(1, Ordering::SeqCst);
+                Err(format!("Sink {} is no longer running", route.config.name))
+            }
+        }
+    }
+
+    fn dead_letter_filter(sink: Option<&str>, event_ids: Option<&[String]>) -> Document {
+        let mut filter = doc! {};
+        if let Some(sink) = sink {
+            filter.insert("sink", sink);
+        }
+        if let Some(event_ids) = event_ids {
+            filter.insert("event_id", doc! { "$in": event_ids.to_vec() });
+        }
+        filter
+    }
+
+    pub async fn dead_letters(&self, sink: Option<&str>, limit: i64) -> Result<Vec<Document>, mongodb::error::Error> {
+        let options = FindOptions::builder().sort(doc! { "failed_at": -1 }).limit(limit).build();
+        let mut cursor = self.dead_letters.find(SinkRouter::dead_letter_filter(sink, None), options).await?;
+        let mut dead_letters = vec![];
+
+        while let Some(dead_letter) = cursor.next().await {
+            dead_letters.push(dead_letter?);
+        }
+
+        Ok(dead_letters)
+    }
+
+    /// Queues dead letters again on the sink they failed on and removes them from
+    /// `dead_letters`. Returns how many were replayed.
+    pub async fn replay_dead_letters(&self, sink: Option<&str>, event_ids: Option<&[String]>) -> Result<usize, String> {
+        let filter = SinkRouter::dead_letter_filter(sink, event_ids);
+        let mut cursor = self.dead_letters.find(filter, None).await.map_err(|err| err.to_string())?;
+        let routes = self.all_routes();
+        let mut replayed = 0;
+
+        while let Some(dead_letter) = cursor.next().await {
+            let dead_letter = dead_letter.map_err(|err| err.to_string())?;
+            let sink_name = dead_letter.get_str("sink").unwrap_or_default();
+            let route = match routes.iter().find(|route| route.config.name == sink_name) {
+                Some(route) => route,
+                None => continue,
+            };
+            let event = match dead_letter.get_document("event").ok().cloned().and_then(IndexedEvent::from_document) {
+                Some(event) => event,
+                None => continue,
+            };
+
+            self.enqueue(route, event, false).await?;
+            let dead_letter_id = dead_letter.get_object_id("_id").map_err(|err| err.to_string())?;
+            self.dead_letters.delete_one(doc! { "_id": dead_letter_id.clone() }, None).await.map_err(|err| err.to_string())?;
+            replayed += 1;
+        }
+
+        Ok(replayed)
+    }
+
+    /// Events queued for delivery across all sinks
+    pub fn outbox_depth(&self) -> usize {
+        self.outbox_depth.load(Ordering::SeqCst)
+    }
+
+    /// When any sink last delivered an event
+    pub fn last_delivery_at(&self) -> Option<DateTime<Utc>> {
+        match self.last_delivery_at.load(Ordering::SeqCst) {
+            0 => None,
+            millis => Some(Utc.timestamp_millis(millis)),
+        }
+    }
+}
+
+/// Delivers queued events one at a time, taking live events before backfilled ones.
+async fn run_sink(config: SinkConfig, sink: Box<dyn EventSink>, mut receiver: mpsc::Receiver<IndexedEvent>, mut backfill_receiver: mpsc::Receiver<IndexedEvent>, outbox_depth: Arc<AtomicUsize>, last_delivery_at: Arc<AtomicI64>, dead_letters: Collection<Document>) {
+    loop {
+        let event = tokio::select! {
+            biased;
+            Some(event) = receiver.recv() => event,
+            Some(event) = backfill_receiver.recv() => event,
+            else => break,
+        };
+        let mut attempt = 1;
+
+        loop {
+            metrics::DELIVERY_ATTEMPTS.with_label_values(&[&config.name]).inc();
+            let timer = metrics::DELIVERY_LATENCY.with_label_values(&[&config.name]).start_timer();
+            let delivered = sink.deliver(&event).await;
+            timer.observe_duration();
+
+            match delivered {
+                Ok(()) => {
+                    metrics::DELIVERY_SUCCESSES.with_label_values(&[&config.name]).inc();
+                    last_delivery_at.store(Utc::now().timestamp_millis(), Ordering::SeqCst);
+                    break;
+                }
+                Err(err) if attempt < config.retry.max_attempts => {
+                    metrics::DELIVERY_FAILURES.with_label_values(&[&config.name]).inc();
+                    warn!(sink = %config.name, event_id = %event.event_id, attempt, "Delivery failed: {}", err);
+                    tokio::time::sleep(config.retry.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    metrics::DELIVERY_FAILURES.with_label_values(&[&config.name]).inc();
+                    error!(sink = %config.name, event_id = %event.event_id, attempts = attempt, "Giving up on delivery, moving the event to dead letters: {}", err);
+                    let dead_letter = doc! {
+                        "sink": config.name.clone(),
+                        "event_id": event.event_id.clone(),
+                        "event": event.to_document(),
+                        "error": err,
+                        "attempts": attempt as i64,
+                        "failed_at": Utc::now(),
+                    };
+
+                    if let Err(err) = dead_letters.insert_one(dead_letter, None).await {
+                        error!(sink = %config.name, event_id = %event.event_id, "Failed to store dead letter: {:?}", err);
+                    }
+                    break;
+                }
+            }
+        }
+
+        outbox_depth.fetch_sub(1, Ordering::SeqCst);
+    }
+}
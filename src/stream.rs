@@ -0,0 +1,344 @@
+use std::time::Duration;
+use actix::{ Actor, ActorContext, AsyncContext, StreamHandler };
+use actix_web::{ web, Error, HttpRequest, HttpResponse, http::StatusCode, web::Bytes };
+use actix_web_actors::ws;
+use qstring::{ QString };
+use tokio::sync::{ broadcast, mpsc };
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::{ IntervalStream, ReceiverStream };
+use tracing::error;
+
+use crate::admin_api::api_error;
+use crate::auth::{ RequireScope, Scope };
+use crate::events::{ EventPosition, IndexedEvent };
+use crate::event_log::EventLog;
+use crate::http_server::AppState;
+
+/// Events a subscriber may fall behind the indexer before it is disconnected.
+const BROADCAST_CAPACITY: usize = 10_000;
+const CLIENT_QUEUE_SIZE: usize = 1_000;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Payload fields naming the accounts an event involves.
+const ACCOUNT_FIELDS: [&str; 5] = ["owner_id", "old_owner_id", "new_owner_id", "buyer_id", "bidder_id"];
+/// Blocks behind the last processed one a client of the public listener may resume from,
+/// so an anonymous client cannot replay the whole event log.
+const PUBLIC_REPLAY_BLOCKS: u64 = 10_000;
+
+/// How far back clients of a listener may resume, `None` for no limit.
+#[derive(Clone, Copy)]
+struct ReplayLimit(Option<u64>);
+
+/// Fans the events of every processed block out to the connected stream clients.
+#[derive(Clone)]
+pub struct EventBroadcast {
+    sender: broadcast::Sender<IndexedEvent>,
+}
+
+impl EventBroadcast {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, events: &[IndexedEvent]) {
+        for event in events {
+            // Sending only fails while nobody is subscribed
+            let _ = self.sender.send(event.clone());
+        }
+    }
+}
+
+/// Which events a client wants, from the `contract_id`, `token_id`, `account_id` and
+/// comma separated `event_types` query parameters.
+#[derive(Default)]
+struct StreamFilter {
+    contract_id: Option<String>,
+    token_id: Option<String>,
+    account_id: Option<String>,
+    event_types: Option<Vec<String>>,
+}
+
+impl StreamFilter {
+    fn from_query(query_string: &QString) -> Self {
+        Self {
+            contract_id: query_string.get("contract_id").map(str::to_string),
+            token_id: query_string.get("token_id").map(str::to_string),
+            account_id: query_string.get("account_id").map(str::to_string),
+            event_types: query_string.get("event_types").map(|list| list.split(',').map(|item| item.trim().to_string()).collect()),
+        }
+    }
+
+    /// The contract matches both the emitting contract and, for market events, the NFT contract.
+    fn matches(&self, event: &IndexedEvent) -> bool {
+        let data = event.payload.data();
+
+        if let Some(event_types) = &self.event_types {
+            if !event_types.iter().any(|event_type| event_type == event.payload.event_type()) {
+                return false;
+            }
+        }
+        if let Some(contract_id) = &self.contract_id {
+            if &event.contract_id != contract_id && data["nft_contract_id"].as_str() != Some(contract_id.as_str()) {
+                return false;
+            }
+        }
+        if let Some(token_id) = &self.token_id {
+            let in_token_ids = data["token_ids"].as_array().map_or(false, |token_ids| token_ids.iter().any(|id| id.as_str() == Some(token_id.as_str())));
+            if !in_token_ids && data["token_id"].as_str() != Some(token_id.as_str()) {
+                return false;
+            }
+        }
+        if let Some(account_id) = &self.account_id {
+            if !ACCOUNT_FIELDS.iter().any(|field| data[field].as_str() == Some(account_id.as_str())) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Where a client resumes: right after a given event, from a block height, or live only.
+enum Resume {
+    Live,
+    AfterEvent(String),
+    FromHeight(u64),
+}
+
+impl Resume {
+    /// `from_event_id` wins over `from_height`; `Last-Event-ID` is what `EventSource` sends on reconnect.
+    fn from_request(req: &HttpRequest, query_string: &QString) -> Result<Self, HttpResponse> {
+        let last_event_id = req.headers().get("Last-Event-ID").and_then(|value| value.to_str().ok());
+
+        if let Some(event_id) = query_string.get("from_event_id").or(last_event_id) {
+            return Ok(Resume::AfterEvent(event_id.to_string()));
+        }
+
+        match query_string.get("from_height").map(str::parse::<u64>) {
+            Some(Ok(height)) => Ok(Resume::FromHeight(height)),
+            Some(Err(_)) => Err(api_error(StatusCode::BAD_REQUEST, "invalid_height", "`from_height` must be a block height")),
+            None => Ok(Resume::Live),
+        }
+    }
+}
+
+/// Lowest height a client may resume from under `limit`, `None` when it may resume from anywhere.
+fn min_resume_height(limit: ReplayLimit, data: &AppState) -> Result<Option<u64>, HttpResponse> {
+    let blocks = match limit.0 {
+        Some(blocks) => blocks,
+        None => return Ok(None),
+    };
+
+    match data.health.last_height() {
+        Some(last_height) => Ok(Some(last_height.saturating_sub(blocks))),
+        None => Err(api_error(StatusCode::SERVICE_UNAVAILABLE, "not_ready", "Resuming is possible once the first block was processed")),
+    }
+}
+
+/// Replays the event log from the resume point and then follows the live events, sending
+/// every matching one to the returned receiver. Subscribing before replaying means no event
+/// is missed in between; live events already replayed are skipped by their chain position.
+/// A client that cannot keep up is disconnected and should resume from its last event.
+async fn open_feed(filter: StreamFilter, resume: Resume, min_height: Option<u64>, event_log: EventLog, broadcast: &EventBroadcast) -> Result<mpsc::Receiver<IndexedEvent>, HttpResponse> {
+    let mut live = broadcast.sender.subscribe();
+    let (sender, receiver) = mpsc::channel(CLIENT_QUEUE_SIZE);
+
+    let (from_height, after) = match resume {
+        Resume::Live => (None, None),
+        Resume::FromHeight(height) => (Some(height), None),
+        Resume::AfterEvent(event_id) => match event_log.get(&event_id).await {
+            Ok(Some(event)) => (Some(event.block_height), Some(event.sort_key())),
+            Ok(None) => return Err(api_error(StatusCode::NOT_FOUND, "not_found", format!("Event '{}' is not in the event log", event_id))),
+            Err(err) => return Err(api_error(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err)),
+        },
+    };
+    if let (Some(from_height), Some(min_height)) = (from_height, min_height) {
+        if from_height < min_height {
+            return Err(api_error(StatusCode::BAD_REQUEST, "resume_too_old", format!("Resuming is possible from height {} on", min_height)));
+        }
+    }
+
+    actix::spawn(async move {
+        let mut last_sent = after;
+
+        if let Some(from_height) = from_height {
+            let mut cursor = match event_log.read_from(from_height).await {
+                Ok(cursor) => cursor,
+                Err(err) => {
+                    error!("Failed to replay the event log for a stream client: {:?}", err);
+                    return;
+                }
+            };
+
+            let mut block_events: Vec<IndexedEvent> = vec![];
+            while let Some(document) = cursor.next().await {
+                let event = match document.ok().and_then(IndexedEvent::from_document) {
+                    Some(event) => event,
+                    None => continue,
+                };
+
+                if block_events.first().map_or(false, |first| first.block_height != event.block_height) {
+                    if !send_in_order(&mut block_events, &filter, &sender, &mut last_sent).await {
+                        return;
+                    }
+                }
+                block_events.push(event);
+            }
+
+            if !send_in_order(&mut block_events, &filter, &sender, &mut last_sent).await {
+                return;
+            }
+        }
+
+        loop {
+            let event = match live.recv().await {
+                Ok(event) => event,
+                Err(_) => return,
+            };
+            if last_sent.as_ref().map_or(false, |last_sent| &event.sort_key() <= last_sent) {
+                continue;
+            }
+
+            last_sent = Some(event.sort_key());
+            if filter.matches(&event) && sender.send(event).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(receiver)
+}
+
+/// Sends one block's replayed events in chain order, which the event log's string event ids
+/// do not give for logs with more than ten entries. Returns `false` once the client is gone.
+async fn send_in_order(block_events: &mut Vec<IndexedEvent>, filter: &StreamFilter, sender: &mpsc::Sender<IndexedEvent>, last_sent: &mut Option<EventPosition>) -> bool {
+    block_events.sort_by_key(|event| event.sort_key());
+
+    for event in block_events.drain(..) {
+        if last_sent.as_ref().map_or(false, |last_sent| &event.sort_key() <= last_sent) {
+            continue;
+        }
+
+        *last_sent = Some(event.sort_key());
+        if filter.matches(&event) && sender.send(event).await.is_err() {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn stream_message(event: &IndexedEvent) -> String {
+    serde_json::to_string(event).expect("Event is always serializable")
+}
+
+/// `GET /stream/sse`: each event is sent with its event id as the SSE `id`, so browsers resume on their own.
+async fn stream_sse(data: web::Data<AppState>, limit: web::Data<ReplayLimit>, req: HttpRequest) -> HttpResponse {
+    let query_string = QString::from(req.query_string());
+    let resume = match Resume::from_request(&req, &query_string) {
+        Ok(resume) => resume,
+        Err(response) => return response,
+    };
+    let min_height = match min_resume_height(**limit, &data) {
+        Ok(min_height) => min_height,
+        Err(response) => return response,
+    };
+    let feed = match open_feed(StreamFilter::from_query(&query_string), resume, min_height, data.event_log.clone(), &data.event_broadcast).await {
+        Ok(feed) => feed,
+        Err(response) => return response,
+    };
+
+    // The feed ends with a `None`, which also stops the heartbeats and closes the response
+    let events = ReceiverStream::new(feed)
+        .map(|event| Some(format!("id: {}\nevent: {}\ndata: {}\n\n", event.event_id, event.payload.event_type(), stream_message(&event))))
+        .chain(tokio_stream::once(None));
+    let heartbeats = IntervalStream::new(tokio::time::interval(HEARTBEAT_INTERVAL)).map(|_| Some(": heartbeat\n\n".to_string()));
+    let body = events.merge(heartbeats)
+        .take_while(Option::is_some)
+        .map(|chunk| Ok::<_, Error>(Bytes::from(chunk.unwrap_or_default())));
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+/// Forwards the feed of one WebSocket client as text frames.
+struct StreamSession {
+    feed: Option<mpsc::Receiver<IndexedEvent>>,
+}
+
+impl Actor for StreamSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(feed) = self.feed.take() {
+            ctx.add_stream(ReceiverStream::new(feed));
+        }
+        ctx.run_interval(HEARTBEAT_INTERVAL, |_, ctx| ctx.ping(b""));
+    }
+}
+
+impl StreamHandler<IndexedEvent> for StreamSession {
+    fn handle(&mut self, event: IndexedEvent, ctx: &mut Self::Context) {
+        ctx.text(stream_message(&event));
+    }
+
+    /// The feed only ends when the client fell too far behind.
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason { code: ws::CloseCode::Again, description: Some("Fell behind, resume from the last event id".to_string()) }));
+        ctx.stop();
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for StreamSession {
+    fn handle(&mut self, message: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match message {
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+/// `GET /stream/ws`: every event is sent as a JSON text frame.
+async fn stream_ws(data: web::Data<AppState>, limit: web::Data<ReplayLimit>, req: HttpRequest, payload: web::Payload) -> Result<HttpResponse, Error> {
+    let query_string = QString::from(req.query_string());
+    let resume = match Resume::from_request(&req, &query_string) {
+        Ok(resume) => resume,
+        Err(response) => return Ok(response),
+    };
+    let min_height = match min_resume_height(**limit, &data) {
+        Ok(min_height) => min_height,
+        Err(response) => return Ok(response),
+    };
+    let feed = match open_feed(StreamFilter::from_query(&query_string), resume, min_height, data.event_log.clone(), &data.event_broadcast).await {
+        Ok(feed) => feed,
+        Err(response) => return Ok(response),
+    };
+
+    ws::start(StreamSession { feed: Some(feed) }, &req, payload)
+}
+
+fn streams(limit: ReplayLimit) -> actix_web::Scope {
+    web::scope("/stream")
+        .app_data(web::Data::new(limit))
+        .route("/sse", web::get().to(stream_sse))
+        .route("/ws", web::get().to(stream_ws))
+}
+
+/// Stream routes for the public listener, served without a token and only resuming
+/// from recent blocks.
+pub fn configure_public(cfg: &mut web::ServiceConfig) {
+    cfg.service(streams(ReplayLimit(Some(PUBLIC_REPLAY_BLOCKS))));
+}
+
+/// Stream routes for the admin listener, requiring the `read` scope.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(streams(ReplayLimit(None)).wrap(RequireScope(Scope::Read)));
+}
@@ -0,0 +1,340 @@
+use std::time::Instant;
+use async_trait::async_trait;
+use bson::{ Bson, doc, document::Document, oid::ObjectId };
+use hmac::{ Hmac, Mac, NewMac };
+use mongodb::{ Database, Collection, options::FindOptions };
+use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+use sha2::Sha256;
+use tokio_stream::StreamExt;
+use tracing::{ error, warn };
+
+use crate::events::IndexedEvent;
+use crate::metrics;
+use crate::sinks::{ self, EventSink, RetryPolicy, SinkConfig, SinkKind, SinkRouter };
+
+pub const WEBHOOKS_COLLECTION: &str = "webhook_subscriptions";
+pub const DELIVERIES_COLLECTION: &str = "webhook_deliveries";
+
+/// A consumer registered through the admin API. Every event it accepts is POSTed to
+/// `url`, signed with `secret`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WebhookSubscription {
+    pub id: String,
+    pub url: String,
+    pub secret: String,
+    /// Event types delivered, all of them when omitted
+    pub event_types: Option<Vec<String>>,
+    /// Contracts whose events are delivered, all of them when omitted
+    pub contracts: Option<Vec<String>>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    pub enabled: bool,
+    pub created_at: String,
+    pub created_by: String,
+}
+
+impl WebhookSubscription {
+    /// Name of the subscription's sink, as recorded on its dead letters.
+    pub fn sink_name(&self) -> String {
+        format!("webhook:{}", self.id)
+    }
+
+    pub fn sink_config(&self) -> SinkConfig {
+        SinkConfig {
+            name: self.sink_name(),
+            kind: SinkKind::Http { url: self.url.clone() },
+            event_types: self.event_types.clone(),
+            contracts: self.contracts.clone(),
+            retry: self.retry.clone(),
+        }
+    }
+
+    /// The subscription as returned by the admin API, without its secret.
+    pub fn view(&self) -> Value {
+        let mut view = serde_json::to_value(self).expect("Webhook subscription is always serializable");
+        if let Some(view) = view.as_object_mut() {
+            view.remove("secret");
+        }
+        view
+    }
+
+    pub fn to_document(&self) -> Document {
+        let value = serde_json::to_value(self).expect("Webhook subscription is always serializable");
+        let mut document = match Bson::from(value) {
+            Bson::Document(document) => document,
+            _ => unreachable!("Webhook subscription always serializes to an object"),
+        };
+
+        document.remove("id");
+        document.insert("_id", self.id.clone());
+        document
+    }
+
+    pub fn from_document(mut document: Document) -> Option<Self> {
+        let id = document.get_str("_id").ok()?.to_string();
+        document.remove("_id");
+        document.insert("id", id);
+
+        serde_json::from_value(Bson::Document(document).into_relaxed_extjson()).ok()
+    }
+
+    pub fn apply(&mut self, update: &WebhookUpdate) {
+        if let Some(url) = &update.url {
+            self.url = url.clone();
+        }
+        if let Some(secret) = &update.secret {
+            self.secret = secret.clone();
+        }
+        if let Some(event_types) = &update.event_types {
+            self.event_types = Some(event_types.clone());
+        }
+        if let Some(contracts) = &update.contracts {
+            self.contracts = Some(contracts.clone());
+        }
+        if let Some(retry) = &update.retry {
+            self.retry = retry.clone();
+        }
+        if let Some(enabled) = update.enabled {
+            self.enabled = enabled;
+        }
+    }
+
+    /// Starts, restarts or stops the subscription's delivery to match its settings.
+    pub fn route(&self, sink_router: &SinkRouter, database: &Database) {
+        match self.enabled {
+            true => sink_router.set_webhook(&self.id, self.sink_config(), Box::new(WebhookSink::new(self, database))),
+            false => sink_router.remove_webhook(&self.id),
+        }
+    }
+}
+
+/// Fields of a subscription that can be changed; omitted fields keep their value.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct WebhookUpdate {
+    pub url: Option<String>,
+    pub secret: Option<String>,
+    pub event_types: Option<Vec<String>>,
+    pub contracts: Option<Vec<String>>,
+    pub retry: Option<RetryPolicy>,
+    pub enabled: Option<bool>,
+}
+
+impl WebhookUpdate {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(url) = &self.url {
+            validate_url(url)?;
+        }
+        if self.secret.as_ref().map_or(false, |secret| secret.is_empty()) {
+            return Err("`secret` must not be empty".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct NewWebhook {
+    pub url: String,
+    pub secret: String,
+    #[serde(flatten)]
+    pub fields: WebhookUpdate,
+}
+
+impl NewWebhook {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_url(&self.url)?;
+        if self.secret.is_empty() {
+            return Err("`secret` must not be empty".to_string());
+        }
+
+        self.fields.validate()
+    }
+
+    pub fn into_subscription(self, created_by: &str) -> WebhookSubscription {
+        let mut subscription = WebhookSubscription {
+            id: ObjectId::new().to_hex(),
+            url: self.url,
+            secret: self.secret,
+            event_types: None,
+            contracts: None,
+            retry: RetryPolicy::default(),
+            enabled: true,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            created_by: created_by.to_string(),
+        };
+        subscription.apply(&self.fields);
+
+        subscription
+    }
+}
+
+fn validate_url(url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(()),
+        _ => Err(format!("'{}' is not an http or https url", url)),
+    }
+}
+
+#[derive(Clone)]
+pub struct WebhookStore {
+    database: Database,
+}
+
+impl WebhookStore {
+    pub fn new(database: Database) -> Self {
+        Self { database }
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.database.collection(WEBHOOKS_COLLECTION)
+    }
+
+    pub async fn list(&self) -> Result<Vec<WebhookSubscription>, mongodb::error::Error> {
+        let options = FindOptions::builder().sort(doc! { "created_at": 1 }).build();
+        let mut cursor = self.collection().find(None, options).await?;
+        let mut subscriptions = vec![];
+
+        while let Some(document) = cursor.next().await {
+            match WebhookSubscription::from_document(document?) {
+                Some(subscription) => subscriptions.push(subscription),
+                None => warn!("Skipping malformed webhook subscription"),
+            }
+        }
+
+        Ok(subscriptions)
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<WebhookSubscription>, mongodb::error::Error> {
+        let document = self.collection().find_one(doc! { "_id": id }, None).await?;
+        Ok(document.and_then(WebhookSubscription::from_document))
+    }
+
+    pub async fn insert(&self, subscription: &WebhookSubscription) -> Result<(), mongodb::error::Error> {
+        self.collection().insert_one(subscription.to_document(), None).await?;
+        Ok(())
+    }
+
+    pub async fn replace(&self, subscription: &WebhookSubscription) -> Result<(), mongodb::error::Error> {
+        self.collection().replace_one(doc! { "_id": subscription.id.clone() }, subscription.to_document(), None).await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<bool, mongodb::error::Error> {
+        let result = self.collection().delete_one(doc! { "_id": id }, None).await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Most recent delivery attempts of a subscription.
+    pub async fn deliveries(&self, id: &str, limit: i64) -> Result<Vec<Document>, mongodb::error::Error> {
+        let deliveries: Collection<Document> = self.database.collection(DELIVERIES_COLLECTION);
+        let options = FindOptions::builder().sort(doc! { "delivered_at": -1 }).limit(limit).build();
+        let mut cursor = deliveries.find(doc! { "subscription_id": id }, options).await?;
+        let mut history = vec![];
+
+        while let Some(document) = cursor.next().await {
+            let mut document = document?;
+            document.remove("_id");
+            history.push(document);
+        }
+
+        Ok(history)
+    }
+}
+
+/// POSTs the whole event as JSON with an `X-Capacitor-Signature: sha256=<hex>` header,
+/// the HMAC-SHA256 of the body keyed with the subscription's secret. Every attempt is
+/// recorded in `webhook_deliveries`.
+pub struct WebhookSink {
+    subscription_id: String,
+    /// `webhook:<id>`, the metrics label; the URL may carry secrets
+    sink_name: String,
+    url: String,
+    secret: String,
+    client: reqwest::Client,
+    deliveries: Collection<Document>,
+}
+
+impl WebhookSink {
+    pub fn new(subscription: &WebhookSubscription, database: &Database) -> Self {
+        Self {
+            subscription_id: subscription.id.clone(),
+            sink_name: subscription.sink_name(),
+            url: subscription.url.clone(),
+            secret: subscription.secret.clone(),
+            client: sinks::http_client(),
+            deliveries: database.collection(DELIVERIES_COLLECTION),
+        }
+    }
+
+    fn signature(&self, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    async fn record(&self, event: &IndexedEvent, status: Option<u16>, error: Option<&str>, started: Instant) {
+        let delivery = doc! {
+            "subscription_id": self.subscription_id.clone(),
+            "event_id": event.event_id.clone(),
+            "event_type": event.payload.event_type(),
+            "url": self.url.clone(),
+            "status": status.map(|status| status as i32),
+            "success": error.is_none(),
+            "error": error,
+            "duration_ms": started.elapsed().as_millis() as i64,
+            "delivered_at": chrono::Utc::now(),
+        };
+
+        if let Err(err) = self.deliveries.insert_one(delivery, None).await {
+            error!(subscription_id = %self.subscription_id, event_id = %event.event_id, "Failed to record webhook delivery: {:?}", err);
+        }
+    }
+}
+
+#[async_trait]
+impl EventSink for WebhookSink {
+    async fn deliver(&self, event: &IndexedEvent) -> Result<(), String> {
+        let body = serde_json::to_vec(event).map_err(|err| err.to_string())?;
+        let started = Instant::now();
+
+        let res = self.client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .header("X-Capacitor-Event", event.payload.event_type())
+            .header("X-Capacitor-Delivery", event.event_id.clone())
+            .header("X-Capacitor-Signature", self.signature(&body))
+            .body(body)
+            .send()
+            .await;
+
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                let err = err.to_string();
+                metrics::HTTP_DELIVERY_RESPONSES.with_label_values(&[&self.sink_name, "error"]).inc();
+                self.record(event, None, Some(&err), started).await;
+                return Err(err);
+            }
+        };
+
+        let status = res.status();
+        metrics::HTTP_DELIVERY_RESPONSES.with_label_values(&[&self.sink_name, status.as_str()]).inc();
+
+        match status.is_success() {
+            true => {
+                self.record(event, Some(status.as_u16()), None, started).await;
+                Ok(())
+            }
+            false => {
+                let err = format!("Received response status {:?}", status);
+                self.record(event, Some(status.as_u16()), Some(&err), started).await;
+                Err(err)
+            }
+        }
+    }
+}